@@ -1,4 +1,8 @@
-use doh_dns::{client::HyperDnsClient, Dns, DnsHttpsServer};
+use doh_dns::{
+    client::{HyperDnsClient, Proxy, ServerOptions},
+    stub::StubServer,
+    Dns, DnsHttpsServer,
+};
 use log::{Level, LevelFilter, Metadata, Record};
 #[macro_use]
 extern crate prettytable;
@@ -12,8 +16,10 @@ static LOGGER: SimpleLogger = SimpleLogger;
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 3 {
+    let serve = args.len() >= 2 && args[1] == "serve";
+    if (serve && args.len() > 3) || (!serve && args.len() != 3) {
         println!("Usage: {} rtype name", args[0]);
+        println!("       {} serve [address]", args[0]);
         return Ok(());
     }
 
@@ -21,11 +27,29 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .map(|()| log::set_max_level(LevelFilter::Info))
         .unwrap();
 
-    let dns: Dns<HyperDnsClient> = Dns::with_servers(&[
-        DnsHttpsServer::Google(Duration::from_secs(2)),
-        DnsHttpsServer::Cloudflare1_1_1_1(Duration::from_secs(10)),
-    ])
+    // Connections go through the proxy set in `HTTPS_PROXY`, if any.
+    let mut options = ServerOptions::new();
+    if let Some(proxy) = Proxy::from_env()? {
+        options = options.proxy(proxy);
+    }
+    let client = HyperDnsClient::builder().options(options).build()?;
+    let dns: Dns<HyperDnsClient> = Dns::with_client(
+        client,
+        &[
+            DnsHttpsServer::Google(Duration::from_secs(2)),
+            DnsHttpsServer::Cloudflare1_1_1_1(Duration::from_secs(10)),
+        ],
+    )
     .unwrap();
+
+    // Answers the queries received on UDP and TCP, by default on port 53 of localhost.
+    if serve {
+        let addr = args.get(2).map_or("127.0.0.1:53", |a| a.as_str()).parse()?;
+        println!("Listening on {}", addr);
+        StubServer::new(dns).serve(addr).await?;
+        return Ok(());
+    }
+
     match dns.resolve_str_type(&args[2], &args[1]).await {
         Ok(responses) => {
             if responses.is_empty() {
@@ -0,0 +1,213 @@
+// Connections bound to a network device. hyper's connector cannot set socket options before
+// connecting so these connections are made here instead.
+use crate::proxy::unbracket;
+use futures_util::future::{self, BoxFuture, FutureExt};
+use hyper::{
+    client::{connect::dns::Name, HttpConnector},
+    Uri,
+};
+use std::{
+    error::Error,
+    ffi::CString,
+    io,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+    task::{self, Poll},
+    time::Duration,
+};
+use tokio::net::TcpStream;
+use tower_service::Service;
+
+// The device a connection is bound to along with the settings otherwise applied by hyper.
+#[derive(Debug)]
+pub(crate) struct Device {
+    pub(crate) name: CString,
+    pub(crate) local_address: Option<IpAddr>,
+    pub(crate) connect_timeout: Option<Duration>,
+    pub(crate) nodelay: bool,
+    pub(crate) keepalive: Option<Duration>,
+}
+
+impl Device {
+    // Connects to the first address that accepts the connection.
+    async fn connect(&self, addrs: Vec<IpAddr>, port: u16) -> io::Result<TcpStream> {
+        let mut last_error = None;
+        // Like hyper, only the addresses of the family of the local address are used.
+        for ip in addrs.into_iter().filter(|ip| {
+            self.local_address
+                .is_none_or(|local| local.is_ipv4() == ip.is_ipv4())
+        }) {
+            let addr = SocketAddr::new(ip, port);
+            let connecting = self.connect_addr(addr);
+            let result = match self.connect_timeout {
+                Some(t) => tokio::time::timeout(t, connecting)
+                    .await
+                    .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut))),
+                None => connecting.await,
+            };
+            match result {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable)))
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn connect_addr(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        use socket2::{Domain, Protocol, Socket, Type};
+        let domain = if addr.is_ipv4() {
+            Domain::ipv4()
+        } else {
+            Domain::ipv6()
+        };
+        let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+        socket.bind_device(Some(&self.name))?;
+        if let Some(local) = self.local_address {
+            socket.bind(&SocketAddr::new(local, 0).into())?;
+        }
+        let stream = TcpStream::connect_std(socket.into_tcp_stream(), &addr).await?;
+        stream.set_nodelay(self.nodelay)?;
+        stream.set_keepalive(self.keepalive)?;
+        Ok(stream)
+    }
+
+    // Binding to a device is refused when the client is built on other systems.
+    #[cfg(not(target_os = "linux"))]
+    async fn connect_addr(&self, _addr: SocketAddr) -> io::Result<TcpStream> {
+        Err(io::Error::other(
+            "binding to a device is only supported on Linux",
+        ))
+    }
+}
+
+// Connects over TCP with hyper's connector or, if a device is given, with a socket bound to
+// it. Names are resolved with the same resolver in both cases.
+#[derive(Clone)]
+pub(crate) struct BindConnector<R> {
+    http: HttpConnector<R>,
+    resolver: R,
+    device: Option<Arc<Device>>,
+}
+
+impl<R> BindConnector<R> {
+    pub(crate) fn new(
+        http: HttpConnector<R>,
+        resolver: R,
+        device: Option<Device>,
+    ) -> BindConnector<R> {
+        BindConnector {
+            http,
+            resolver,
+            device: device.map(Arc::new),
+        }
+    }
+}
+
+impl<R> Service<Uri> for BindConnector<R>
+where
+    R: Service<Name> + Clone + Send + 'static,
+    R::Response: Iterator<Item = IpAddr>,
+    R::Error: Into<Box<dyn Error + Send + Sync>>,
+    R::Future: Send,
+    HttpConnector<R>: Service<Uri, Response = TcpStream>,
+    <HttpConnector<R> as Service<Uri>>::Future: Send + 'static,
+    <HttpConnector<R> as Service<Uri>>::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    type Response = TcpStream;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = BoxFuture<'static, Result<TcpStream, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let device = match self.device {
+            Some(ref device) => Arc::clone(device),
+            None => return self.http.call(uri).map(|r| r.map_err(Into::into)).boxed(),
+        };
+        let host = unbracket(uri.host().unwrap_or_default()).to_owned();
+        let port = uri_port(&uri);
+        let mut resolver = self.resolver.clone();
+        async move {
+            let addrs = match host.parse::<IpAddr>() {
+                Ok(ip) => vec![ip],
+                Err(_) => {
+                    let name = Name::from_str(&host)?;
+                    future::poll_fn(|cx| resolver.poll_ready(cx))
+                        .await
+                        .map_err(Into::into)?;
+                    resolver.call(name).await.map_err(Into::into)?.collect()
+                }
+            };
+            Ok(device.connect(addrs, port).await?)
+        }
+        .boxed()
+    }
+}
+
+// Returns the port of a URI, or the default port of its scheme.
+pub(crate) fn uri_port(uri: &Uri) -> u16 {
+    uri.port_u16()
+        .unwrap_or(if uri.scheme_str() == Some("http") {
+            80
+        } else {
+            443
+        })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use hyper::client::connect::dns::GaiResolver;
+    use std::net::Ipv4Addr;
+    use tokio::net::TcpListener;
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_bind_device() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let _ = listener.accept().await;
+        });
+        let device = Device {
+            name: CString::new("lo").unwrap(),
+            local_address: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            connect_timeout: Some(Duration::from_secs(1)),
+            nodelay: true,
+            keepalive: None,
+        };
+        let resolver = GaiResolver::new();
+        let mut connector = BindConnector::new(
+            HttpConnector::new_with_resolver(resolver.clone()),
+            resolver,
+            Some(device),
+        );
+        let uri = format!("http://localhost:{}", port).parse().unwrap();
+        let stream = connector.call(uri).await.unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip(), Ipv4Addr::LOCALHOST);
+        assert!(stream.nodelay().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_local_address_family() {
+        // No address of the family of the local address is found.
+        let device = Device {
+            name: CString::new("lo").unwrap(),
+            local_address: Some("::1".parse().unwrap()),
+            connect_timeout: None,
+            nodelay: false,
+            keepalive: None,
+        };
+        let e = device
+            .connect(vec![IpAddr::V4(Ipv4Addr::LOCALHOST)], 443)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AddrNotAvailable);
+        assert_eq!(uri_port(&"http://[::1]/".parse().unwrap()), 80);
+        assert_eq!(uri_port(&"https://[::1]/".parse().unwrap()), 443);
+    }
+}
@@ -0,0 +1,223 @@
+// Filtering of names found in blocklists, with exceptions given in allow-lists.
+use crate::status::{ExtendedErrorCode, RCode};
+use crate::{BlockResponse, DnsAnswer, DnsComment, DnsResponse};
+use std::{collections::HashSet, net::IpAddr};
+
+// The TTL of the unspecified addresses answered for blocked names.
+const BLOCKED_TTL: u32 = 300;
+// Names of hosts files that are not blocked since they are local.
+const LOCAL_NAMES: [&str; 7] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "0.0.0.0",
+];
+
+// The blocked and allowed domains, lowercase and puny encoded without the trailing dot.
+// Each entry also applies to the names under it.
+#[derive(Debug, Clone)]
+pub(crate) struct Blocklist {
+    blocked: HashSet<String>,
+    allowed: HashSet<String>,
+    pub(crate) response: BlockResponse,
+}
+
+impl Blocklist {
+    pub(crate) fn new() -> Blocklist {
+        Blocklist {
+            blocked: HashSet::new(),
+            allowed: HashSet::new(),
+            response: BlockResponse::NxDomain,
+        }
+    }
+
+    // Adds the entries of a list returning how many were found. Adblock exceptions are
+    // added to the allowed domains.
+    pub(crate) fn load(&mut self, contents: &str, allow: bool) -> usize {
+        let mut count = 0;
+        for line in contents.lines() {
+            for (exception, domain) in entries(line) {
+                if allow || exception {
+                    self.allowed.insert(domain);
+                } else {
+                    self.blocked.insert(domain);
+                }
+                count += 1;
+            }
+        }
+        count
+    }
+
+    // Returns true if the name or one of its parents is blocked and none is allowed.
+    pub(crate) fn is_blocked(&self, name: &str) -> bool {
+        if self.blocked.is_empty() {
+            return false;
+        }
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let mut blocked = false;
+        let mut suffix = name.as_str();
+        loop {
+            if self.allowed.contains(suffix) {
+                return false;
+            }
+            blocked |= self.blocked.contains(suffix);
+            match suffix.find('.') {
+                Some(i) => suffix = &suffix[i + 1..],
+                None => return blocked,
+            }
+        }
+    }
+
+    // Builds the response to a blocked query. It has a Blocked extended error in the
+    // comment like the servers give them.
+    pub(crate) fn blocked_response(&self, name: &str, rtype: u32) -> DnsResponse {
+        let comment = Some(DnsComment::One(format!(
+            "EDE({}): {}",
+            u16::from(ExtendedErrorCode::Blocked),
+            ExtendedErrorCode::Blocked
+        )));
+        let (status, data) = match self.response {
+            BlockResponse::NxDomain => (RCode::NXDomain, None),
+            BlockResponse::Refused => (RCode::Refused, None),
+            BlockResponse::Unspecified => match rtype {
+                1 => (RCode::NoError, Some("0.0.0.0")),
+                28 => (RCode::NoError, Some("::")),
+                _ => (RCode::NoError, None),
+            },
+        };
+        DnsResponse {
+            Status: u16::from(status),
+            Question: None,
+            Answer: data.map(|data| {
+                vec![DnsAnswer {
+                    name: format!("{}.", name.trim_end_matches('.')),
+                    r#type: rtype,
+                    TTL: BLOCKED_TTL,
+                    data: data.to_owned(),
+                }]
+            }),
+            Comment: comment,
+        }
+    }
+}
+
+// Returns the domains of a line of a list, with whether they are adblock exceptions. The
+// line may be a domain, a hosts file entry or an adblock `||domain^` rule. Other rules,
+// such as those with options or for element hiding, are skipped.
+fn entries(line: &str) -> Vec<(bool, String)> {
+    let line = line.trim();
+    if line.starts_with('!') || line.starts_with('[') || line.contains("##") || line.contains("#@#")
+    {
+        return vec![];
+    }
+    let line = line.split('#').next().unwrap_or_default().trim();
+    if let Some(rule) = line.strip_prefix("@@||") {
+        return adblock_domain(rule)
+            .map(|d| (true, d))
+            .into_iter()
+            .collect();
+    }
+    if let Some(rule) = line.strip_prefix("||") {
+        return adblock_domain(rule)
+            .map(|d| (false, d))
+            .into_iter()
+            .collect();
+    }
+    let fields = line.split_whitespace().collect::<Vec<_>>();
+    let names = match fields.as_slice() {
+        [_] => &fields[..1],
+        [ip, names @ ..] if ip.parse::<IpAddr>().is_ok() => names,
+        _ => return vec![],
+    };
+    names
+        .iter()
+        .filter(|name| !LOCAL_NAMES.contains(&name.to_ascii_lowercase().as_str()))
+        .filter_map(|name| domain(name))
+        .map(|d| (false, d))
+        .collect()
+}
+
+fn adblock_domain(rule: &str) -> Option<String> {
+    domain(rule.strip_suffix('^')?)
+}
+
+// Normalizes a domain of a list, rejecting those with wildcards or paths.
+fn domain(domain: &str) -> Option<String> {
+    let domain = domain.trim_end_matches('.');
+    let valid = !domain.is_empty()
+        && domain
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.');
+    if !valid {
+        return None;
+    }
+    // Underscores are not valid in host names but are found in lists.
+    idna::domain_to_ascii(domain)
+        .ok()
+        .filter(|d| !d.is_empty())
+        .or_else(|| Some(domain.to_ascii_lowercase()))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entries() {
+        assert_eq!(
+            entries("ads.example.com"),
+            vec![(false, "ads.example.com".to_owned())]
+        );
+        assert_eq!(
+            entries("0.0.0.0 ads.example.com Tracker.example.com # trackers"),
+            vec![
+                (false, "ads.example.com".to_owned()),
+                (false, "tracker.example.com".to_owned())
+            ]
+        );
+        assert_eq!(
+            entries("||ads.example.com^"),
+            vec![(false, "ads.example.com".to_owned())]
+        );
+        assert_eq!(
+            entries("@@||cdn.example.com^"),
+            vec![(true, "cdn.example.com".to_owned())]
+        );
+        for skipped in &[
+            "# comment",
+            "! adblock comment",
+            "[Adblock Plus 2.0]",
+            "127.0.0.1 localhost",
+            "||ads.example.com^$third-party",
+            "example.com##.banner",
+            "/ads/*",
+            "*.example.com",
+        ] {
+            assert!(entries(skipped).is_empty(), "{}", skipped);
+        }
+    }
+
+    #[test]
+    fn test_is_blocked() {
+        let mut list = Blocklist::new();
+        assert!(!list.is_blocked("example.com"));
+        let count = list.load(
+            "example.com\n0.0.0.0 tracker.net\n||ads.org^\n@@||good.example.com^\n",
+            false,
+        );
+        assert_eq!(count, 4);
+        list.load("ok.tracker.net", true);
+        assert!(list.is_blocked("example.com"));
+        assert!(list.is_blocked("www.Example.com."));
+        assert!(list.is_blocked("x.ads.org"));
+        assert!(!list.is_blocked("good.example.com"));
+        assert!(!list.is_blocked("www.good.example.com"));
+        assert!(!list.is_blocked("ok.tracker.net"));
+        assert!(list.is_blocked("tracker.net"));
+        assert!(!list.is_blocked("example.org"));
+        assert!(!list.is_blocked("com"));
+    }
+}
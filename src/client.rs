@@ -1,75 +1,838 @@
 //! HTTPS client to query DoH servers.
+//!
+//! Two TLS backends are available through cargo features: `native-tls` (the default),
+//! which provides [HyperDnsClient], and `rustls`, which provides [RustlsDnsClient] with
+//! bundled root certificates and no dependency on the system TLS library.
+//!
+//! Both clients can be configured with a [ClientBuilder] to trust a custom CA bundle or
+//! to pin the keys expected from each server.
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+use crate::bind::{BindConnector, Device};
+use crate::error::DnsError;
+pub use crate::proxy::Proxy;
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+use crate::proxy::ProxyConnector;
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+use crate::tls;
+use crate::DnsHttpsServer;
 use async_trait::async_trait;
-use futures_util::future::{err, ok, Ready};
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+use futures_util::future::{self, BoxFuture, FutureExt};
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
 use hyper::{
-    client::{connect::dns::Name, HttpConnector},
-    error::Result as HyperResult,
-    Body, Client, Request, Response, Uri,
+    client::{
+        connect::{dns::Name, Connect},
+        HttpConnector,
+    },
+    header::{HeaderValue, USER_AGENT},
+    Client, Request,
 };
-use hyper_tls::HttpsConnector;
+use hyper::{error::Result as HyperResult, Body, Response, Uri};
+use std::{collections::HashMap, fmt, marker::PhantomData, net::IpAddr, time::Duration};
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
 use std::{
+    ffi::CString,
     io,
-    net::{IpAddr, Ipv4Addr},
+    net::Ipv4Addr,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
     task::{self, Poll},
+    vec,
 };
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
 use tower_service::Service;
 
 /// Creates a `GET` request over the given `URI` and returns its response. It is used to
 /// request data from DoH servers.
 #[async_trait]
 pub trait DnsClient: Default {
+    /// Creates a new client. An error is returned if the client cannot be initialized,
+    /// such as when the TLS backend fails to load. By default, it uses [Default].
+    fn new() -> Result<Self, DnsError> {
+        Ok(Self::default())
+    }
+
     async fn get(&self, uri: Uri) -> HyperResult<Response<Body>>;
+
+    /// Returns true if a redirect from one URI to another can be followed without losing
+    /// the options of the server, such as its pins, CA bundle or client certificate, which
+    /// may not apply to another host. By default, all redirects can be followed.
+    fn redirect_allowed(&self, _from: &Uri, _to: &Uri) -> bool {
+        true
+    }
+}
+
+/// TLS options used when connecting to a server. They are given to a [ClientBuilder],
+/// either as the default of all servers or for a specific one.
+#[derive(Clone, Debug, Default)]
+pub struct ServerOptions {
+    pins: Vec<String>,
+    ca_bundle: Option<Vec<u8>>,
+    identity: Option<Identity>,
+    proxy: Option<Proxy>,
+    local_address: Option<IpAddr>,
+    device: Option<String>,
+}
+
+// The client certificate presented to servers that request one. It is only read by the
+// TLS backends.
+#[derive(Clone)]
+#[cfg_attr(not(any(feature = "native-tls", feature = "rustls")), allow(dead_code))]
+enum Identity {
+    Pem {
+        cert_chain: Vec<u8>,
+        key: Vec<u8>,
+    },
+    #[cfg(feature = "native-tls")]
+    Pkcs12 {
+        der: Vec<u8>,
+        password: String,
+    },
+}
+
+// The key and password are left out.
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Identity::Pem { .. } => write!(f, "Identity::Pem"),
+            #[cfg(feature = "native-tls")]
+            Identity::Pkcs12 { .. } => write!(f, "Identity::Pkcs12"),
+        }
+    }
+}
+
+impl ServerOptions {
+    /// Creates options that validate servers with the default root certificates of the
+    /// backend.
+    pub fn new() -> ServerOptions {
+        ServerOptions::default()
+    }
+
+    /// Pins the base64 encoded SHA-256 hash of a SubjectPublicKeyInfo, as produced by
+    /// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst
+    /// -sha256 -binary | base64`. Once a pin is given, connections are refused unless one
+    /// of the certificates in the validated chain of the server has a pinned key, in
+    /// addition to the usual validation. A failed connection returns
+    /// [QueryError::PinMismatch].
+    ///
+    /// Since `native-tls` only gives access to the server certificate, [HyperDnsClient]
+    /// only accepts a pinned key of the server certificate itself.
+    ///
+    /// [QueryError::PinMismatch]: crate::error::QueryError::PinMismatch
+    pub fn pin_sha256(mut self, pin: &str) -> ServerOptions {
+        self.pins.push(pin.to_owned());
+        self
+    }
+
+    /// Trusts only the certificates of the given PEM bundle instead of the default root
+    /// certificates.
+    pub fn ca_bundle_pem(mut self, pem: &[u8]) -> ServerOptions {
+        self.ca_bundle = Some(pem.to_vec());
+        self
+    }
+
+    /// Authenticates to servers that request a client certificate (mutual TLS) with the
+    /// given PEM certificate chain, starting with the client certificate, and its PEM
+    /// encoded PKCS#8 private key. `rustls` also accepts a PKCS#1 RSA private key.
+    pub fn identity_pem(mut self, cert_chain: &[u8], key: &[u8]) -> ServerOptions {
+        self.identity = Some(Identity::Pem {
+            cert_chain: cert_chain.to_vec(),
+            key: key.to_vec(),
+        });
+        self
+    }
+
+    /// Authenticates to servers that request a client certificate (mutual TLS) with the
+    /// given DER encoded PKCS#12 archive and its password. PKCS#12 archives are only
+    /// supported by [HyperDnsClient]. [ClientBuilder::build] fails otherwise.
+    #[cfg(feature = "native-tls")]
+    pub fn identity_pkcs12(mut self, der: &[u8], password: &str) -> ServerOptions {
+        self.identity = Some(Identity::Pkcs12 {
+            der: der.to_vec(),
+            password: password.to_owned(),
+        });
+        self
+    }
+
+    /// Connects to the servers through the given proxy, which can be taken from the
+    /// environment with [Proxy::from_env].
+    pub fn proxy(mut self, proxy: Proxy) -> ServerOptions {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Binds the connections to the given local address, such as the address of the
+    /// network interface to use. It replaces the address given with
+    /// [ClientBuilder::local_address]. Only the server addresses of the same family are
+    /// then used.
+    pub fn local_address(mut self, address: IpAddr) -> ServerOptions {
+        self.local_address = Some(address);
+        self
+    }
+
+    /// Binds the connections to the given network device, such as `eth1` or a VRF device,
+    /// with `SO_BINDTODEVICE`. It usually requires the `CAP_NET_RAW` capability.
+    /// Devices are only supported on Linux. [ClientBuilder::build] fails otherwise.
+    pub fn bind_device(mut self, device: &str) -> ServerOptions {
+        self.device = Some(device.to_owned());
+        self
+    }
+}
+
+/// Builds a [DnsClient] with custom TLS options for all or some of the servers. Servers
+/// are matched by the host of their URL. It is created with [HyperDnsClient::builder] or
+/// [RustlsDnsClient::builder].
+///
+/// ```no_run
+/// # #[cfg(feature = "rustls")]
+/// # fn main() -> Result<(), doh_dns::error::DnsError> {
+/// use doh_dns::{client::{RustlsDnsClient, ServerOptions}, Dns, DnsHttpsServer};
+/// use std::time::Duration;
+///
+/// let server = DnsHttpsServer::Custom(
+///     "https://doh.internal.example/resolve".to_owned(),
+///     Duration::from_secs(3),
+/// );
+/// let client = RustlsDnsClient::builder()
+///     .server(
+///         &server,
+///         ServerOptions::new().pin_sha256("47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="),
+///     )
+///     .build()?;
+/// let dns = Dns::with_client(client, &[server])?;
+/// # Ok(())
+/// # }
+/// # #[cfg(not(feature = "rustls"))]
+/// # fn main() {}
+/// ```
+pub struct ClientBuilder<C> {
+    options: ServerOptions,
+    servers: HashMap<String, ServerOptions>,
+    settings: ConnectionSettings,
+    client: PhantomData<C>,
+}
+
+// Settings of the HTTP connections shared by all servers. The defaults are those of hyper.
+#[derive(Clone, Debug)]
+struct ConnectionSettings {
+    keep_alive: bool,
+    pool_idle_timeout: Option<Duration>,
+    pool_max_idle_per_host: usize,
+    http2: bool,
+    http2_only: bool,
+    http2_keep_alive_interval: Option<Duration>,
+    http2_keep_alive_timeout: Duration,
+    connect_timeout: Option<Duration>,
+    tcp_keepalive: Option<Duration>,
+    nodelay: bool,
+    local_address: Option<IpAddr>,
+    user_agent: Option<String>,
+}
+
+impl Default for ConnectionSettings {
+    fn default() -> ConnectionSettings {
+        ConnectionSettings {
+            keep_alive: true,
+            pool_idle_timeout: Some(Duration::from_secs(90)),
+            pool_max_idle_per_host: usize::MAX,
+            http2: true,
+            http2_only: false,
+            http2_keep_alive_interval: None,
+            http2_keep_alive_timeout: Duration::from_secs(20),
+            connect_timeout: None,
+            tcp_keepalive: None,
+            nodelay: false,
+            local_address: None,
+            user_agent: None,
+        }
+    }
+}
+
+impl<C> ClientBuilder<C> {
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    fn new() -> ClientBuilder<C> {
+        ClientBuilder {
+            options: ServerOptions::default(),
+            servers: HashMap::new(),
+            settings: ConnectionSettings::default(),
+            client: PhantomData,
+        }
+    }
+
+    /// Sets the options used for servers without their own options.
+    pub fn options(mut self, options: ServerOptions) -> ClientBuilder<C> {
+        self.options = options;
+        self
+    }
+
+    /// Sets the options used for the given server. They replace the default options.
+    pub fn server(mut self, server: &DnsHttpsServer, options: ServerOptions) -> ClientBuilder<C> {
+        self.servers.insert(server_host(server.uri()), options);
+        self
+    }
+
+    /// Keeps connections open to reuse them for later queries. Defaults to `true`.
+    pub fn keep_alive(mut self, keep_alive: bool) -> ClientBuilder<C> {
+        self.settings.keep_alive = keep_alive;
+        self
+    }
+
+    /// Sets how long an idle connection is kept open. `None` keeps them open until the
+    /// server closes them. Defaults to 90 seconds.
+    pub fn pool_idle_timeout(mut self, timeout: Option<Duration>) -> ClientBuilder<C> {
+        self.settings.pool_idle_timeout = timeout;
+        self
+    }
+
+    /// Sets the maximum number of idle connections kept open to each server. Defaults to
+    /// no limit.
+    pub fn pool_max_idle_per_host(mut self, max_idle: usize) -> ClientBuilder<C> {
+        self.settings.pool_max_idle_per_host = max_idle;
+        self
+    }
+
+    /// Uses HTTP/2 with the servers that support it, as recommended by RFC 8484. Queries
+    /// are then multiplexed over a single connection to each server. Defaults to `true`.
+    ///
+    /// The protocol is negotiated with ALPN and HTTP/1.1 is used with servers that do not
+    /// support HTTP/2.
+    pub fn http2(mut self, http2: bool) -> ClientBuilder<C> {
+        self.settings.http2 = http2;
+        self
+    }
+
+    /// Only uses HTTP/2 to connect to the servers. Defaults to `false`.
+    pub fn http2_only(mut self, http2_only: bool) -> ClientBuilder<C> {
+        self.settings.http2_only = http2_only;
+        self
+    }
+
+    /// Sends HTTP/2 pings at the given interval to keep connections open, even when idle,
+    /// such as those established with [Dns::warm_up](crate::Dns::warm_up). Defaults to
+    /// `None`, which disables them.
+    pub fn http2_keep_alive_interval(mut self, interval: Option<Duration>) -> ClientBuilder<C> {
+        self.settings.http2_keep_alive_interval = interval;
+        self
+    }
+
+    /// Sets how long to wait for the answer to a ping before closing the connection.
+    /// Defaults to 20 seconds.
+    pub fn http2_keep_alive_timeout(mut self, timeout: Duration) -> ClientBuilder<C> {
+        self.settings.http2_keep_alive_timeout = timeout;
+        self
+    }
+
+    /// Sets the timeout to establish a TCP connection. Unlike the timeout of each server,
+    /// it does not include the TLS handshake nor the request. Defaults to `None`.
+    pub fn connect_timeout(mut self, timeout: Option<Duration>) -> ClientBuilder<C> {
+        self.settings.connect_timeout = timeout;
+        self
+    }
+
+    /// Sets the interval of the TCP keepalive probes. Defaults to `None`, which disables
+    /// them.
+    pub fn tcp_keepalive(mut self, interval: Option<Duration>) -> ClientBuilder<C> {
+        self.settings.tcp_keepalive = interval;
+        self
+    }
+
+    /// Sets `TCP_NODELAY` on the connections. Defaults to `false`.
+    pub fn nodelay(mut self, nodelay: bool) -> ClientBuilder<C> {
+        self.settings.nodelay = nodelay;
+        self
+    }
+
+    /// Binds the connections to the given local address. Defaults to `None`, which lets
+    /// the system choose.
+    pub fn local_address(mut self, address: Option<IpAddr>) -> ClientBuilder<C> {
+        self.settings.local_address = address;
+        self
+    }
+
+    /// Sets the `User-Agent` header of the requests. Defaults to none.
+    pub fn user_agent(mut self, user_agent: &str) -> ClientBuilder<C> {
+        self.settings.user_agent = Some(user_agent.to_owned());
+        self
+    }
+
+    // Builds the hyper clients using the connector created for each set of options.
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    fn clients<T, F>(self, connector: F) -> Result<Clients<T>, DnsError>
+    where
+        T: Connect + Clone + Send + Sync + 'static,
+        F: Fn(&ServerOptions, &ConnectionSettings) -> Result<T, DnsError>,
+    {
+        let settings = &self.settings;
+        let user_agent = match settings.user_agent {
+            Some(ref user_agent) => {
+                Some(HeaderValue::from_str(user_agent).map_err(|e| DnsError::Client(e.into()))?)
+            }
+            None => None,
+        };
+        let mut builder = Client::builder();
+        builder
+            .pool_idle_timeout(settings.pool_idle_timeout)
+            .pool_max_idle_per_host(if settings.keep_alive {
+                settings.pool_max_idle_per_host
+            } else {
+                0
+            })
+            .http2_only(settings.http2_only)
+            .http2_keep_alive_interval(settings.http2_keep_alive_interval)
+            .http2_keep_alive_timeout(settings.http2_keep_alive_timeout)
+            .http2_keep_alive_while_idle(true);
+        let mut servers = HashMap::with_capacity(self.servers.len());
+        for (host, options) in self.servers.iter() {
+            servers.insert(host.clone(), builder.build(connector(options, settings)?));
+        }
+        Ok(Clients {
+            default: builder.build(connector(&self.options, settings)?),
+            servers,
+            user_agent,
+        })
+    }
+}
+
+// The hyper clients of a DNS client. Servers with their own options have their own client.
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+struct Clients<T> {
+    default: Client<T>,
+    servers: HashMap<String, Client<T>>,
+    user_agent: Option<HeaderValue>,
+}
+
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+impl<T: Connect + Clone + Send + Sync + 'static> Clients<T> {
+    async fn get(&self, uri: Uri) -> HyperResult<Response<Body>> {
+        let client = uri
+            .host()
+            .and_then(|host| self.servers.get(host))
+            .unwrap_or(&self.default);
+        client
+            .request(dns_request(uri, self.user_agent.as_ref()))
+            .await
+    }
+
+    // Redirects to another host are only followed from the servers that use the default
+    // options, which apply to every host.
+    fn redirect_allowed(&self, from: &Uri, to: &Uri) -> bool {
+        match from.host() {
+            Some(host) => to.host() == Some(host) || !self.servers.contains_key(host),
+            None => true,
+        }
+    }
+}
+
+// Returns the host of a server URL which is used to find its options.
+fn server_host(uri: &str) -> String {
+    uri.parse::<Uri>()
+        .ok()
+        .and_then(|uri| uri.host().map(str::to_owned))
+        .unwrap_or_default()
 }
 
 /// Hyper-based DNS client over SSL and with a static resolver to resolve DNS server names
 /// such as `dns.google` since Google does not accept request over `8.8.8.8` like Cloudflare
-/// does over `1.1.1.1`.
+/// does over `1.1.1.1`. It uses the TLS library of the system through `native-tls`.
+#[cfg(feature = "native-tls")]
 pub struct HyperDnsClient {
-    client: Client<HttpsConnector<HttpConnector<UrlStaticResolver>>>,
+    clients: Clients<NativeTlsConnector>,
+}
+
+#[cfg(feature = "native-tls")]
+impl HyperDnsClient {
+    /// Creates a builder to set the TLS options of the servers.
+    pub fn builder() -> ClientBuilder<HyperDnsClient> {
+        ClientBuilder::new()
+    }
+}
+
+#[cfg(feature = "native-tls")]
+impl ClientBuilder<HyperDnsClient> {
+    /// Builds the client. An error is returned if the TLS backend cannot be initialized or
+    /// a CA bundle, pin or identity is invalid.
+    pub fn build(self) -> Result<HyperDnsClient, DnsError> {
+        Ok(HyperDnsClient {
+            clients: self.clients(native_tls_connector)?,
+        })
+    }
+}
+
+#[cfg(feature = "native-tls")]
+fn native_tls_connector(
+    options: &ServerOptions,
+    settings: &ConnectionSettings,
+) -> Result<NativeTlsConnector, DnsError> {
+    let pins = tls::decode_pins(&options.pins)?;
+    let mut builder = native_tls_crate::TlsConnector::builder();
+    if let Some(ref pem) = options.ca_bundle {
+        let certs = tls::pem_certificates(pem);
+        if certs.is_empty() {
+            return Err(DnsError::Client(
+                "no certificates found in CA bundle".into(),
+            ));
+        }
+        for cert in certs {
+            let cert = native_tls_crate::Certificate::from_pem(cert)
+                .map_err(|e| DnsError::Client(e.into()))?;
+            builder.add_root_certificate(cert);
+        }
+        builder.disable_built_in_roots(true);
+    }
+    if let Some(ref identity) = options.identity {
+        let identity = match *identity {
+            Identity::Pem {
+                ref cert_chain,
+                ref key,
+            } => native_tls_crate::Identity::from_pkcs8(cert_chain, key),
+            Identity::Pkcs12 {
+                ref der,
+                ref password,
+            } => native_tls_crate::Identity::from_pkcs12(der, password),
+        };
+        builder.identity(identity.map_err(|e| DnsError::Client(e.into()))?);
+    }
+    if settings.http2_only {
+        builder.request_alpns(&["h2"]);
+    } else if settings.http2 {
+        builder.request_alpns(&["h2", "http/1.1"]);
+    }
+    let tls = builder.build().map_err(|e| DnsError::Client(e.into()))?;
+    Ok(NativeTlsConnector {
+        http: http_connector(options, settings)?,
+        tls,
+        pins: Arc::new(pins),
+    })
+}
+
+// Connects to the servers over TLS like the connector of hyper-tls, and then checks that
+// the key of the server certificate is pinned, if any.
+#[cfg(feature = "native-tls")]
+#[derive(Clone)]
+struct NativeTlsConnector {
+    http: TcpConnector,
+    tls: native_tls_crate::TlsConnector,
+    pins: Arc<Vec<[u8; 32]>>,
+}
+
+#[cfg(feature = "native-tls")]
+impl Service<Uri> for NativeTlsConnector {
+    type Response = crate::native::TlsStream<tokio::net::TcpStream>;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let https = dst.scheme_str() == Some("https");
+        let host = crate::proxy::unbracket(dst.host().unwrap_or_default()).to_owned();
+        let connector = self.tls.clone();
+        let pins = Arc::clone(&self.pins);
+        let connecting = self.http.call(dst);
+        async move {
+            if !https {
+                return Err("invalid URL, scheme is not https".into());
+            }
+            let tcp = connecting.await?;
+            let tls = crate::native::connect(&connector, &host, tcp).await?;
+            if !pins.is_empty() {
+                let cert = tls.peer_certificate()?.ok_or(tls::PinMismatch)?;
+                let der = cert.to_der().map_err(io::Error::other)?;
+                if !tls::is_pinned(&pins, &der) {
+                    return Err(tls::PinMismatch.into());
+                }
+            }
+            Ok(tls)
+        }
+        .boxed()
+    }
 }
 
+#[cfg(feature = "native-tls")]
 impl Default for HyperDnsClient {
+    /// Creates a new client.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the TLS backend cannot be initialized. Use [DnsClient::new] to get an
+    /// error instead.
     fn default() -> HyperDnsClient {
-        let mut http_connector = HttpConnector::new_with_resolver(UrlStaticResolver::new());
-        http_connector.enforce_http(false);
-        let mut connector = HttpsConnector::from((
-            http_connector,
-            native_tls::TlsConnector::new().unwrap().into(),
-        ));
-        connector.https_only(true);
-        HyperDnsClient {
-            client: Client::builder().keep_alive(true).build(connector),
-        }
+        <HyperDnsClient as DnsClient>::new().expect("TLS backend initialization")
     }
 }
 
+#[cfg(feature = "native-tls")]
 #[async_trait]
 impl DnsClient for HyperDnsClient {
+    fn new() -> Result<HyperDnsClient, DnsError> {
+        HyperDnsClient::builder().build()
+    }
+
     async fn get(&self, uri: Uri) -> HyperResult<Response<Body>> {
-        // The reason to build a request manually is to set the Accept header required by
-        // DNS servers.
-        let req = Request::builder()
-            .method("GET")
-            .uri(uri)
-            .header("Accept", "application/dns-json")
-            .body(Body::default())
-            .expect("request builder");
-        self.client.request(req).await
+        self.clients.get(uri).await
+    }
+
+    fn redirect_allowed(&self, from: &Uri, to: &Uri) -> bool {
+        self.clients.redirect_allowed(from, to)
     }
 }
 
+/// Hyper-based DNS client that uses `rustls` for TLS with the root certificates bundled
+/// from `webpki-roots`. Like [HyperDnsClient], it statically resolves the DoH server names.
+/// Cloudflare's servers, given by their address, are verified under `cloudflare-dns.com`,
+/// while custom servers must be given by name.
+#[cfg(feature = "rustls")]
+pub struct RustlsDnsClient {
+    clients: Clients<RustlsConnector>,
+}
+
+#[cfg(feature = "rustls")]
+impl RustlsDnsClient {
+    /// Creates a builder to set the TLS options of the servers.
+    pub fn builder() -> ClientBuilder<RustlsDnsClient> {
+        ClientBuilder::new()
+    }
+}
+
+#[cfg(feature = "rustls")]
+impl ClientBuilder<RustlsDnsClient> {
+    /// Builds the client. An error is returned if a CA bundle or a pin is invalid.
+    pub fn build(self) -> Result<RustlsDnsClient, DnsError> {
+        Ok(RustlsDnsClient {
+            clients: self.clients(rustls_connector)?,
+        })
+    }
+}
+
+#[cfg(feature = "rustls")]
+fn rustls_connector(
+    options: &ServerOptions,
+    settings: &ConnectionSettings,
+) -> Result<RustlsConnector, DnsError> {
+    let mut config = tokio_rustls::rustls::ClientConfig::new();
+    if settings.http2_only {
+        config.set_protocols(&[b"h2".to_vec()]);
+    } else if settings.http2 {
+        config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    }
+    match options.ca_bundle {
+        Some(ref pem) => match config.root_store.add_pem_file(&mut pem.as_slice()) {
+            Ok((valid, _)) if valid > 0 => {}
+            _ => {
+                return Err(DnsError::Client(
+                    "no valid certificates in CA bundle".into(),
+                ))
+            }
+        },
+        None => config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+    }
+    if !options.pins.is_empty() {
+        let verifier = tls::PinningVerifier::new(tls::decode_pins(&options.pins)?);
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(verifier));
+    }
+    match options.identity {
+        Some(Identity::Pem {
+            ref cert_chain,
+            ref key,
+        }) => {
+            use tokio_rustls::rustls::internal::pemfile;
+            let certs = match pemfile::certs(&mut cert_chain.as_slice()) {
+                Ok(certs) if !certs.is_empty() => certs,
+                _ => {
+                    return Err(DnsError::Client(
+                        "no certificates found in client certificate chain".into(),
+                    ))
+                }
+            };
+            let key = pemfile::pkcs8_private_keys(&mut key.as_slice())
+                .ok()
+                .and_then(|keys| keys.into_iter().next())
+                .or_else(|| {
+                    pemfile::rsa_private_keys(&mut key.as_slice())
+                        .ok()
+                        .and_then(|keys| keys.into_iter().next())
+                })
+                .ok_or_else(|| DnsError::Client("no private key found for client".into()))?;
+            config
+                .set_single_client_cert(certs, key)
+                .map_err(|e| DnsError::Client(e.into()))?;
+        }
+        #[cfg(feature = "native-tls")]
+        Some(Identity::Pkcs12 { .. }) => {
+            return Err(DnsError::Client(
+                "PKCS#12 identities are not supported by the rustls backend".into(),
+            ))
+        }
+        None => {}
+    }
+    Ok(RustlsConnector {
+        http: http_connector(options, settings)?,
+        tls: Arc::new(config),
+        names: &SERVER_NAMES,
+    })
+}
+
+// The names verified for the servers given by an IP address, since webpki cannot verify
+// an address. They are in the certificates of these servers.
+#[cfg(feature = "rustls")]
+const SERVER_NAMES: [(IpAddr, &str); 2] = [
+    (IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)), "cloudflare-dns.com"),
+    (IpAddr::V4(Ipv4Addr::new(1, 0, 0, 1)), "cloudflare-dns.com"),
+];
+
+// Connects to the servers over TLS like the connector of hyper-rustls, except that servers
+// given by an IP address are verified under the name of their certificate.
+#[cfg(feature = "rustls")]
+#[derive(Clone)]
+struct RustlsConnector {
+    http: TcpConnector,
+    tls: Arc<tokio_rustls::rustls::ClientConfig>,
+    names: &'static [(IpAddr, &'static str)],
+}
+
+#[cfg(feature = "rustls")]
+impl Service<Uri> for RustlsConnector {
+    type Response = hyper_rustls::MaybeHttpsStream<tokio::net::TcpStream>;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let https = dst.scheme_str() == Some("https");
+        let host = crate::proxy::unbracket(dst.host().unwrap_or_default()).to_owned();
+        let name = match host.parse::<IpAddr>() {
+            Ok(ip) => self
+                .names
+                .iter()
+                .find(|(address, _)| *address == ip)
+                .map(|(_, name)| name.to_string()),
+            Err(_) => Some(host),
+        };
+        let connector = tokio_rustls::TlsConnector::from(Arc::clone(&self.tls));
+        let connecting = self.http.call(dst);
+        async move {
+            if !https {
+                return Err("invalid URL, scheme is not https".into());
+            }
+            let tcp = connecting.await?;
+            let name = name.ok_or("servers given by an IP address cannot be verified by rustls")?;
+            let dns_name = tokio_rustls::webpki::DNSNameRef::try_from_ascii_str(&name)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid server name"))?;
+            let tls = connector
+                .connect(dns_name, tcp)
+                .await
+                .map_err(io::Error::other)?;
+            Ok(hyper_rustls::MaybeHttpsStream::Https(tls))
+        }
+        .boxed()
+    }
+}
+
+#[cfg(feature = "rustls")]
+impl Default for RustlsDnsClient {
+    /// Creates a new client.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the client cannot be initialized. Use [DnsClient::new] to get an error
+    /// instead.
+    fn default() -> RustlsDnsClient {
+        <RustlsDnsClient as DnsClient>::new().expect("TLS backend initialization")
+    }
+}
+
+#[cfg(feature = "rustls")]
+#[async_trait]
+impl DnsClient for RustlsDnsClient {
+    fn new() -> Result<RustlsDnsClient, DnsError> {
+        RustlsDnsClient::builder().build()
+    }
+
+    async fn get(&self, uri: Uri) -> HyperResult<Response<Body>> {
+        self.clients.get(uri).await
+    }
+
+    fn redirect_allowed(&self, from: &Uri, to: &Uri) -> bool {
+        self.clients.redirect_allowed(from, to)
+    }
+}
+
+// The connector used under TLS.
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+type TcpConnector = ProxyConnector<BindConnector<UrlStaticResolver>>;
+
+// Creates the connector used under TLS. It resolves server names statically and connects
+// through the proxy of the options if one is given.
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+fn http_connector(
+    options: &ServerOptions,
+    settings: &ConnectionSettings,
+) -> Result<TcpConnector, DnsError> {
+    let local_address = options.local_address.or(settings.local_address);
+    let resolver = UrlStaticResolver::new();
+    let mut http_connector = HttpConnector::new_with_resolver(resolver.clone());
+    http_connector.enforce_http(false);
+    http_connector.set_connect_timeout(settings.connect_timeout);
+    http_connector.set_keepalive(settings.tcp_keepalive);
+    http_connector.set_nodelay(settings.nodelay);
+    http_connector.set_local_address(local_address);
+    let device = match options.device {
+        Some(ref name) if cfg!(target_os = "linux") => Some(Device {
+            name: CString::new(name.as_str()).map_err(|e| DnsError::Client(e.into()))?,
+            local_address,
+            connect_timeout: settings.connect_timeout,
+            nodelay: settings.nodelay,
+            keepalive: settings.tcp_keepalive,
+        }),
+        Some(_) => {
+            return Err(DnsError::Client(
+                "binding to a device is only supported on Linux".into(),
+            ))
+        }
+        None => None,
+    };
+    Ok(ProxyConnector::new(
+        BindConnector::new(http_connector, resolver, device),
+        options.proxy.clone(),
+    ))
+}
+
+// The reason to build a request manually is to set the Accept header required by DNS
+// servers.
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+fn dns_request(uri: Uri, user_agent: Option<&HeaderValue>) -> Request<Body> {
+    let mut request = Request::builder()
+        .method("GET")
+        .uri(uri)
+        .header("Accept", "application/dns-json");
+    if let Some(user_agent) = user_agent {
+        request = request.header(USER_AGENT, user_agent);
+    }
+    request.body(Body::default()).expect("request builder")
+}
+
 // This is resolver that statically resolves the Google DNS name to 8.8.8.8 and
 // 8.8.4.4 in a round robin fashion. The Cloudflare IPs are not resolved since those
-// are already statically defined in the request URL.
+// are already statically defined in the request URL. Other names, such as those of custom
+// servers, are resolved by the system.
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
 #[derive(Clone)]
 struct UrlStaticResolver {
     round_robin: Arc<AtomicBool>,
 }
 
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
 impl UrlStaticResolver {
     fn new() -> UrlStaticResolver {
         UrlStaticResolver {
@@ -78,10 +841,11 @@ impl UrlStaticResolver {
     }
 }
 
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
 impl Service<Name> for UrlStaticResolver {
     type Response = UrlStaticAddrs;
     type Error = io::Error;
-    type Future = Ready<Result<UrlStaticAddrs, io::Error>>;
+    type Future = BoxFuture<'static, Result<UrlStaticAddrs, io::Error>>;
 
     fn poll_ready(&mut self, _cx: &mut task::Context<'_>) -> Poll<Result<(), io::Error>> {
         Poll::Ready(Ok(()))
@@ -98,37 +862,44 @@ impl Service<Name> for UrlStaticResolver {
                 rr_ref.store(true, Ordering::Relaxed);
                 IpAddr::V4(Ipv4Addr::new(8, 8, 4, 4))
             };
-            ok(UrlStaticAddrs { inner: Some(addr) })
+            future::ok(UrlStaticAddrs {
+                inner: vec![addr].into_iter(),
+            })
+            .boxed()
         } else {
-            // This should never occur.
-            err(io::Error::from(io::ErrorKind::AddrNotAvailable))
+            async move {
+                let addrs = tokio::net::lookup_host((name.as_str(), 0)).await?;
+                Ok(UrlStaticAddrs {
+                    inner: addrs.map(|addr| addr.ip()).collect::<Vec<_>>().into_iter(),
+                })
+            }
+            .boxed()
         }
     }
 }
 
-// This only contains one IP address so the iterator only needs to go through it once.
+// The addresses found for a name.
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
 struct UrlStaticAddrs {
-    inner: Option<IpAddr>,
+    inner: vec::IntoIter<IpAddr>,
 }
 
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
 impl Iterator for UrlStaticAddrs {
     type Item = IpAddr;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(ip) = self.inner {
-            self.inner = None;
-            Some(ip)
-        } else {
-            None
-        }
+        self.inner.next()
     }
 }
 
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
     use std::str::FromStr;
 
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
     #[tokio::test]
     async fn test_static_resolve() {
         let mut resolver = UrlStaticResolver::new();
@@ -139,5 +910,358 @@ pub mod tests {
         let mut g2 = resolver.call(n.clone()).await.unwrap();
         assert_eq!(g2.next(), Some(IpAddr::V4(Ipv4Addr::new(8, 8, 4, 4))));
         assert_eq!(g2.next(), None);
+        let mut local = resolver
+            .call(Name::from_str("localhost").unwrap())
+            .await
+            .unwrap();
+        assert!(local.next().unwrap().is_loopback());
+    }
+
+    #[test]
+    fn test_server_host() {
+        let server = DnsHttpsServer::Custom(
+            "https://doh.example.com:8443/resolve".to_owned(),
+            Duration::from_secs(1),
+        );
+        assert_eq!(server_host(server.uri()), "doh.example.com");
+        assert_eq!(server_host("not a url"), "");
+    }
+
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    #[test]
+    fn test_user_agent() {
+        let uri = "https://dns.google/resolve".parse::<Uri>().unwrap();
+        let request = dns_request(uri.clone(), None);
+        assert_eq!(request.headers()["Accept"], "application/dns-json");
+        assert!(request.headers().get(USER_AGENT).is_none());
+        let user_agent = HeaderValue::from_static("doh-dns");
+        let request = dns_request(uri, Some(&user_agent));
+        assert_eq!(request.headers()[USER_AGENT], "doh-dns");
+    }
+
+    #[cfg(feature = "native-tls")]
+    #[test]
+    fn test_native_tls_client() {
+        assert!(<HyperDnsClient as DnsClient>::new().is_ok());
+    }
+
+    #[cfg(feature = "native-tls")]
+    #[test]
+    fn test_native_tls_pin_options() {
+        let pinned =
+            ServerOptions::new().pin_sha256("47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=");
+        assert!(HyperDnsClient::builder().options(pinned).build().is_ok());
+        let invalid = ServerOptions::new().pin_sha256("AAAA");
+        assert!(HyperDnsClient::builder().options(invalid).build().is_err());
+    }
+
+    #[cfg(feature = "native-tls")]
+    #[test]
+    fn test_native_tls_ca_bundle() {
+        let bundle = ServerOptions::new().ca_bundle_pem(CA);
+        assert!(HyperDnsClient::builder().options(bundle).build().is_ok());
+        let bundle = ServerOptions::new().ca_bundle_pem(b"not a certificate");
+        assert!(HyperDnsClient::builder().options(bundle).build().is_err());
+    }
+
+    #[cfg(feature = "native-tls")]
+    #[test]
+    fn test_native_tls_identity() {
+        let identity = ServerOptions::new().identity_pem(CLIENT_CERT, CLIENT_KEY);
+        assert!(HyperDnsClient::builder().options(identity).build().is_ok());
+        let identity = ServerOptions::new().identity_pem(b"not a certificate", b"not a key");
+        assert!(HyperDnsClient::builder().options(identity).build().is_err());
+        let identity = ServerOptions::new().identity_pkcs12(b"not an archive", "password");
+        assert!(HyperDnsClient::builder().options(identity).build().is_err());
+    }
+
+    #[cfg(feature = "native-tls")]
+    #[test]
+    fn test_redirect_allowed() {
+        let custom = DnsHttpsServer::Custom(
+            "https://doh.example.com/dns-query".to_owned(),
+            Duration::from_secs(1),
+        );
+        let client = HyperDnsClient::builder()
+            .server(&custom, ServerOptions::new().ca_bundle_pem(CA))
+            .build()
+            .unwrap();
+        let uri = |s: &str| s.parse::<Uri>().unwrap();
+        let from = uri("https://doh.example.com/dns-query");
+        assert!(client.redirect_allowed(&from, &uri("https://doh.example.com/resolve")));
+        assert!(!client.redirect_allowed(&from, &uri("https://evil.example/dns-query")));
+        let google = uri("https://dns.google/resolve");
+        assert!(client.redirect_allowed(&google, &uri("https://evil.example/dns-query")));
+    }
+
+    #[cfg(feature = "native-tls")]
+    #[test]
+    fn test_native_tls_bind() {
+        let server = DnsHttpsServer::Google(Duration::from_secs(1));
+        let bound = ServerOptions::new()
+            .local_address(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+            .bind_device("lo");
+        let client = HyperDnsClient::builder().server(&server, bound).build();
+        assert_eq!(client.is_ok(), cfg!(target_os = "linux"));
+        let invalid = ServerOptions::new().bind_device("l\0o");
+        assert!(HyperDnsClient::builder().options(invalid).build().is_err());
+    }
+
+    #[cfg(feature = "native-tls")]
+    #[test]
+    fn test_native_tls_settings() {
+        let client = HyperDnsClient::builder()
+            .keep_alive(false)
+            .pool_idle_timeout(None)
+            .pool_max_idle_per_host(4)
+            .http2(false)
+            .http2_only(true)
+            .http2_keep_alive_interval(Some(Duration::from_secs(30)))
+            .http2_keep_alive_timeout(Duration::from_secs(5))
+            .connect_timeout(Some(Duration::from_secs(1)))
+            .tcp_keepalive(Some(Duration::from_secs(30)))
+            .nodelay(true)
+            .local_address(Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED)))
+            .user_agent("doh-dns")
+            .build();
+        assert!(client.is_ok());
+        assert!(HyperDnsClient::builder()
+            .user_agent("invalid\nagent")
+            .build()
+            .is_err());
+    }
+
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    const CA: &[u8] = include_bytes!("../testdata/ca.pem");
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    const CLIENT_CERT: &[u8] = include_bytes!("../testdata/client.pem");
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    const CLIENT_KEY: &[u8] = include_bytes!("../testdata/client.key");
+    #[cfg(feature = "native-tls")]
+    const SERVER_P12: &[u8] = include_bytes!("../testdata/server.p12");
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    const SERVER_PIN: &str = "uiUHsVtQ3dKTpl3O5uGAaYrW39KTytWoiKLxrt0Skfg=";
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    const OTHER_PIN: &str = "RqDznM2Nd3VU1XZVzey/O4jWouX26AluqxmqNwebZgc=";
+
+    // The response of the test servers to any request.
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    fn http_response() -> String {
+        let body = r#"{"Status": 0}"#;
+        format!(
+            "HTTP/1.1 200 OK\r\ncontent-type: application/dns-json\r\n\
+             content-length: {}\r\nconnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+
+    // Serves DoH responses in clear text on a local port, closing each connection after a
+    // single request.
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    fn http_server() -> u16 {
+        use std::io::{Read, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let _ = stream.write_all(http_response().as_bytes());
+            }
+        });
+        port
+    }
+
+    // Serves DoH responses over TLS with the given PKCS#12 identity on a local port,
+    // closing each connection after a single request.
+    #[cfg(feature = "native-tls")]
+    fn native_tls_server(p12: &[u8]) -> u16 {
+        use std::io::{Read, Write};
+        let identity = native_tls_crate::Identity::from_pkcs12(p12, "test").unwrap();
+        let acceptor = native_tls_crate::TlsAcceptor::new(identity).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream.map(|s| acceptor.accept(s)) {
+                    Ok(Ok(stream)) => stream,
+                    _ => continue,
+                };
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let _ = stream.write_all(http_response().as_bytes());
+                let _ = stream.shutdown();
+            }
+        });
+        port
+    }
+
+    #[cfg(feature = "native-tls")]
+    #[tokio::test]
+    async fn test_native_tls_pins() {
+        let port = native_tls_server(SERVER_P12);
+        let uri = format!("https://localhost:{}/resolve?name=.&type=NS", port)
+            .parse::<Uri>()
+            .unwrap();
+        let client = |pin| {
+            let options = ServerOptions::new().ca_bundle_pem(CA).pin_sha256(pin);
+            HyperDnsClient::builder().options(options).build().unwrap()
+        };
+        // The server only supports HTTP/1.1, which is used after ALPN.
+        let res = client(SERVER_PIN).get(uri.clone()).await.unwrap();
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.version(), hyper::Version::HTTP_11);
+        let err = client(OTHER_PIN).get(uri).await.unwrap_err();
+        assert!(tls::is_pin_mismatch(&err), "{:?}", err);
+    }
+
+    #[cfg(feature = "native-tls")]
+    #[tokio::test]
+    async fn test_native_tls_https_only() {
+        let uri = format!("http://127.0.0.1:{}/resolve?name=.&type=NS", http_server());
+        let client = <HyperDnsClient as DnsClient>::new().unwrap();
+        let err = client.get(uri.parse().unwrap()).await.unwrap_err();
+        assert!(err.to_string().contains("not https"), "{:?}", err);
+    }
+
+    #[cfg(feature = "rustls")]
+    const SERVER_CERT: &[u8] = include_bytes!("../testdata/server.pem");
+    #[cfg(feature = "rustls")]
+    const SERVER_KEY: &[u8] = include_bytes!("../testdata/server.key");
+
+    // Serves DoH responses over TLS with the given certificate chain and key on a local
+    // port, closing each connection after a single request.
+    #[cfg(feature = "rustls")]
+    async fn rustls_server(cert_chain: &[u8], key: &[u8]) -> u16 {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio_rustls::rustls::{internal::pemfile, NoClientAuth, ServerConfig};
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        let certs = pemfile::certs(&mut &cert_chain[..]).unwrap();
+        let key = pemfile::pkcs8_private_keys(&mut &key[..])
+            .unwrap()
+            .remove(0);
+        config.set_single_cert(certs, key).unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let mut stream = match acceptor.accept(stream).await {
+                        Ok(stream) => stream,
+                        Err(_) => return,
+                    };
+                    let mut request = Vec::new();
+                    let mut buf = [0; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let _ = stream.write_all(http_response().as_bytes()).await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+        port
+    }
+
+    #[cfg(feature = "rustls")]
+    #[tokio::test]
+    async fn test_rustls_connect() {
+        let port = rustls_server(SERVER_CERT, SERVER_KEY).await;
+        let options = ServerOptions::new().ca_bundle_pem(CA);
+        let client = RustlsDnsClient::builder()
+            .options(options.clone())
+            .build()
+            .unwrap();
+        let uri = format!("https://localhost:{}/resolve?name=.&type=NS", port);
+        let res = client.get(uri.parse().unwrap()).await.unwrap();
+        assert_eq!(res.status(), 200);
+        for (pin, pinned) in &[(SERVER_PIN, true), (OTHER_PIN, false)] {
+            let client = RustlsDnsClient::builder()
+                .options(options.clone().pin_sha256(pin))
+                .build()
+                .unwrap();
+            match client.get(uri.parse().unwrap()).await {
+                Ok(res) => assert!(*pinned && res.status() == 200),
+                Err(e) => assert!(!*pinned && tls::is_pin_mismatch(&e), "{:?}", e),
+            }
+        }
+
+        // A server given by an IP address is verified under its known name.
+        let uri = format!("https://127.0.0.1:{}/resolve?name=.&type=NS", port)
+            .parse::<Uri>()
+            .unwrap();
+        let settings = ConnectionSettings::default();
+        let mut connector = rustls_connector(&options, &settings).unwrap();
+        let unknown = Client::builder().build::<_, Body>(connector.clone());
+        assert!(unknown.get(uri.clone()).await.is_err());
+        const NAMES: [(IpAddr, &str); 1] = [(
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            "cloudflare-dns.com",
+        )];
+        connector.names = &NAMES;
+        let known = Client::builder().build::<_, Body>(connector);
+        assert_eq!(known.get(uri).await.unwrap().status(), 200);
+    }
+
+    #[cfg(feature = "rustls")]
+    #[tokio::test]
+    async fn test_rustls_https_only() {
+        let uri = format!("http://127.0.0.1:{}/resolve?name=.&type=NS", http_server());
+        let client = <RustlsDnsClient as DnsClient>::new().unwrap();
+        let err = client.get(uri.parse().unwrap()).await.unwrap_err();
+        assert!(err.to_string().contains("not https"), "{:?}", err);
+    }
+
+    #[cfg(feature = "rustls")]
+    #[test]
+    fn test_rustls_client() {
+        assert!(<RustlsDnsClient as DnsClient>::new().is_ok());
+        let server = DnsHttpsServer::Google(Duration::from_secs(1));
+        let pinned =
+            ServerOptions::new().pin_sha256("47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=");
+        assert!(RustlsDnsClient::builder()
+            .server(&server, pinned)
+            .build()
+            .is_ok());
+        let invalid = ServerOptions::new().pin_sha256("AAAA");
+        assert!(RustlsDnsClient::builder()
+            .server(&server, invalid)
+            .build()
+            .is_err());
+        let bundle = ServerOptions::new().ca_bundle_pem(b"not a certificate");
+        assert!(RustlsDnsClient::builder().options(bundle).build().is_err());
+        let identity = ServerOptions::new().identity_pem(b"not a certificate", b"not a key");
+        assert!(RustlsDnsClient::builder()
+            .options(identity)
+            .build()
+            .is_err());
+        let identity = ServerOptions::new().identity_pem(CLIENT_CERT, CLIENT_KEY);
+        assert!(RustlsDnsClient::builder().options(identity).build().is_ok());
+        #[cfg(feature = "native-tls")]
+        {
+            let identity = ServerOptions::new().identity_pkcs12(b"not an archive", "password");
+            assert!(RustlsDnsClient::builder()
+                .options(identity)
+                .build()
+                .is_err());
+        }
     }
 }
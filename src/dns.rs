@@ -1,47 +1,383 @@
-use crate::client::{DnsClient, HyperDnsClient};
-use crate::error::{DnsError, QueryError};
+use crate::blocklist::Blocklist;
+use crate::client::DnsClient;
+#[cfg(feature = "native-tls")]
+use crate::client::HyperDnsClient;
+#[cfg(feature = "rustls")]
+use crate::client::RustlsDnsClient;
+use crate::error::{Attempt, DnsError, QueryError, ServerAnswer};
+use crate::hosts::Hosts;
+use crate::ratelimit::TokenBucket;
+use crate::rebinding::Rebinding;
 use crate::status::RCode;
-use crate::{Dns, DnsAnswer, DnsHttpsServer, DnsResponse};
-use hyper::Uri;
-use idna;
+use crate::tls;
+use crate::wire;
+use crate::{
+    BlockResponse, Dns, DnsAnswer, DnsHttpsServer, DnsResponse, RateLimited, RebindingResponse,
+    Route,
+};
+use futures_util::{
+    future::join_all,
+    stream::{self, Stream, StreamExt},
+};
+use hyper::{
+    body::HttpBody,
+    header::{HeaderMap, AGE, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, LOCATION},
+    Body, Response, StatusCode, Uri,
+};
 use log::error;
-use std::time::Duration;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 use tokio::time::timeout;
 
+// The maximum size of a response body unless another one is set.
+const DEFAULT_MAX_RESPONSE_SIZE: usize = 64 * 1024;
+// The maximum number of redirects followed by a single request.
+const MAX_REDIRECTS: usize = 5;
+// The media types accepted for the JSON responses.
+const JSON_CONTENT_TYPES: [&str; 2] = ["application/dns-json", "application/json"];
+
+#[cfg(feature = "native-tls")]
 impl Default for Dns<HyperDnsClient> {
     fn default() -> Dns<HyperDnsClient> {
-        Dns {
-            client: HyperDnsClient::default(),
-            servers: vec![
-                DnsHttpsServer::Google(Duration::from_secs(3)),
-                DnsHttpsServer::Cloudflare1_1_1_1(Duration::from_secs(10)),
-            ],
-        }
+        Dns::new(HyperDnsClient::default(), default_servers())
+    }
+}
+
+#[cfg(feature = "rustls")]
+impl Default for Dns<RustlsDnsClient> {
+    fn default() -> Dns<RustlsDnsClient> {
+        Dns::new(RustlsDnsClient::default(), default_servers())
     }
 }
 
+// Google first with a timeout of 3 seconds and Cloudflare second with a timeout of 10 seconds.
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+fn default_servers() -> Vec<DnsHttpsServer> {
+    vec![
+        DnsHttpsServer::Google(Duration::from_secs(3)),
+        DnsHttpsServer::Cloudflare1_1_1_1(Duration::from_secs(10)),
+    ]
+}
+
 impl<C: DnsClient> Dns<C> {
+    pub(crate) fn new(client: C, servers: Vec<DnsHttpsServer>) -> Dns<C> {
+        Dns {
+            client,
+            servers,
+            deadline: None,
+            max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+            routes: HashMap::new(),
+            hosts: Hosts::new(),
+            blocklist: Blocklist::new(),
+            rebinding: Rebinding::new(),
+            consensus: None,
+            rate_limits: HashMap::new(),
+        }
+    }
+
     /// Creates an instance with the given servers along with their respective timeouts
     /// (in seconds). These servers are tried in the given order. If a request fails on
     /// the first one, each subsequent server is tried. Only on certain failures a new
     /// request is retried such as a connection failure or certain server return codes.
+    /// An error is returned if the client cannot be initialized.
     pub fn with_servers(servers: &[DnsHttpsServer]) -> Result<Dns<C>, DnsError> {
         if servers.is_empty() {
             return Err(DnsError::NoServers);
         }
-        Ok(Dns {
-            client: C::default(),
-            servers: servers.to_vec(),
-        })
+        Ok(Dns::new(C::new()?, servers.to_vec()))
+    }
+
+    /// Same as [Dns::with_servers] but with a client already built, such as one with custom
+    /// TLS options created with a [ClientBuilder](crate::client::ClientBuilder).
+    pub fn with_client(client: C, servers: &[DnsHttpsServer]) -> Result<Dns<C>, DnsError> {
+        if servers.is_empty() {
+            return Err(DnsError::NoServers);
+        }
+        Ok(Dns::new(client, servers.to_vec()))
+    }
+
+    /// Sets the total time allowed for a query, including all retries. Unlike the timeouts
+    /// given with each server, which apply to a single attempt, the deadline caps the sum
+    /// of all attempts. The timeout of the last attempt is shortened to the time remaining.
+    /// Once it runs out, the query fails with [DnsError::DeadlineExceeded] whether or not a
+    /// request was in progress. `None`, the default, only applies the per server timeouts.
+    pub fn set_deadline(&mut self, deadline: Option<Duration>) {
+        self.deadline = deadline;
+    }
+
+    /// Sends the queries for a domain and the names under it to other servers, or refuses
+    /// them. The domain may be given as `corp.example` or `*.corp.example`. When routes are
+    /// set for several suffixes of a name, the longest one is used, so `*.corp.example` can
+    /// go to an internal server while `*.example` is refused. Names without a route are
+    /// queried on the default servers. An error is returned if the domain is invalid or no
+    /// servers are given.
+    pub fn set_route(&mut self, domain: &str, route: Route) -> Result<(), DnsError> {
+        if let Route::Servers(ref servers) = route {
+            if servers.is_empty() {
+                return Err(DnsError::NoServers);
+            }
+        }
+        self.routes.insert(route_domain(domain)?, route);
+        Ok(())
+    }
+
+    /// Removes the route set for a domain so that its names are queried on the default
+    /// servers again.
+    pub fn remove_route(&mut self, domain: &str) -> Result<(), DnsError> {
+        self.routes.remove(&route_domain(domain)?);
+        Ok(())
+    }
+
+    // Returns the servers a name is queried on following the route of its longest suffix,
+    // or `None` if it is refused.
+    fn servers_for(&self, name: &str) -> Option<&[DnsHttpsServer]> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let mut suffix = name.as_str();
+        loop {
+            match self.routes.get(suffix) {
+                Some(Route::Servers(servers)) => return Some(servers),
+                Some(Route::Refuse) => return None,
+                None => {}
+            }
+            match suffix.find('.') {
+                Some(i) => suffix = &suffix[i + 1..],
+                None => return Some(&self.servers),
+            }
+        }
+    }
+
+    /// Answers the names of a hosts file, such as the contents of `/etc/hosts`, locally. Each
+    /// name gets an `A` or `AAAA` record with its address and each address a `PTR` record
+    /// to its first name. Queries for these names are never sent to the servers and those
+    /// of other types have no answers. Lines that are not entries are skipped.
+    pub fn load_hosts(&mut self, contents: &str) {
+        self.hosts.load_hosts(contents);
+    }
+
+    /// Answers the records of a simple zone file locally like [Dns::load_hosts]. Each line
+    /// is a record given as `<name> [<ttl>] [IN] <type> <data>`, such as
+    /// `www.corp.example. 300 IN CNAME web.corp.example.`, where names are absolute and the
+    /// type is `A`, `AAAA`, `PTR`, `CNAME` or `TXT`. A `$TTL` line sets the TTL of the
+    /// records that follow without one and comments start with `;`. Local CNAME records are
+    /// followed when answering, and a target that is not local is resolved through the
+    /// servers. If a line cannot be parsed, [DnsError::InvalidHosts] is
+    /// returned and no record is added.
+    pub fn load_zone(&mut self, contents: &str) -> Result<(), DnsError> {
+        self.hosts.load_zone(contents)
+    }
+
+    /// Sets the TTL in seconds of the records loaded from hosts files and of the zone
+    /// records without one. Defaults to 3600 seconds.
+    pub fn set_hosts_ttl(&mut self, ttl: u32) {
+        self.hosts.ttl = ttl;
+    }
+
+    /// Blocks the domains of a list and the names under them. Each line is a domain, a hosts
+    /// file entry such as `0.0.0.0 ads.example.com`, or an adblock rule such as
+    /// `||ads.example.com^`. Adblock exceptions such as `@@||cdn.example.com^` are added to
+    /// the allow-list. Other lines, comments and rules are skipped. Blocked names are never
+    /// sent to the servers and are answered as set with [Dns::set_block_response]. Returns
+    /// the number of entries loaded.
+    pub fn load_blocklist(&mut self, contents: &str) -> usize {
+        self.blocklist.load(contents, false)
+    }
+
+    /// Allows the domains of a list, given in the same formats as [Dns::load_blocklist],
+    /// and the names under them even if they or their parents are blocked. Returns the
+    /// number of entries loaded.
+    pub fn load_allowlist(&mut self, contents: &str) -> usize {
+        self.blocklist.load(contents, true)
+    }
+
+    /// Sets how queries for blocked names are answered. Defaults to
+    /// [BlockResponse::NxDomain].
+    pub fn set_block_response(&mut self, response: BlockResponse) {
+        self.blocklist.response = response;
+    }
+
+    /// Rejects the answers of the servers that have `A` or `AAAA` records with private
+    /// addresses, so that a public name cannot point to internal services. These are the
+    /// unspecified, loopback, private, link-local, shared (CGNAT), unique local, multicast
+    /// and other special-purpose ranges, which include the cloud metadata addresses, and
+    /// IPv6 addresses that embed such IPv4 addresses. Such answers fail with
+    /// [DnsError::PrivateAddress], or have the private addresses dropped as set with
+    /// [Dns::set_rebinding_response], unless the name is allowed with
+    /// [Dns::allow_private_answers]. Local names are not checked. Disabled by default.
+    pub fn set_rebinding_protection(&mut self, enabled: bool) {
+        self.rebinding.enabled = enabled;
+    }
+
+    /// Sets whether answers with private addresses are rejected, the default, or returned
+    /// without the private addresses when the rebinding protection is enabled.
+    pub fn set_rebinding_response(&mut self, response: RebindingResponse) {
+        self.rebinding.response = response;
+    }
+
+    /// Allows a domain and the names under it to be answered with private addresses when
+    /// the rebinding protection is enabled. The domain may be given as `corp.example` or
+    /// `*.corp.example`. An error is returned if the domain is invalid.
+    pub fn allow_private_answers(&mut self, domain: &str) -> Result<(), DnsError> {
+        self.rebinding.allow(route_domain(domain)?);
+        Ok(())
+    }
+
+    /// Removes a domain allowed with [Dns::allow_private_answers].
+    pub fn disallow_private_answers(&mut self, domain: &str) -> Result<(), DnsError> {
+        self.rebinding.disallow(&route_domain(domain)?);
+        Ok(())
+    }
+
+    /// Queries several servers at once to detect a tampered or poisoned one. The first
+    /// `count` servers of the name, those of its route if it has one, are queried and the
+    /// response given by more than half of them is returned. Responses agree when they have
+    /// the same response code and records for the name and its CNAME chain, regardless of
+    /// their TTL, order and how each server formats their data. If no
+    /// response is given by most servers, including those that fail, the query fails with
+    /// [DnsError::Disagreement]. Each server is given its timeout, capped by the deadline.
+    /// `None`, the default, tries the servers one after the other.
+    pub fn set_consensus(&mut self, count: Option<usize>) {
+        self.consensus = count;
+    }
+
+    /// Limits the queries sent to a server, such as to stay within the quotas of a public
+    /// one, with a token bucket that allows `per_second` queries on average and bursts of up
+    /// to `burst` queries. Both are at least 1. When the limit is reached, queries either
+    /// wait for their turn or go to the next server as given by `when_limited`. The limit
+    /// applies to the server wherever it is used, such as in routes.
+    pub fn set_rate_limit(
+        &mut self,
+        server: &DnsHttpsServer,
+        per_second: u32,
+        burst: u32,
+        when_limited: RateLimited,
+    ) {
+        let bucket = TokenBucket::new(per_second, burst, when_limited);
+        self.rate_limits.insert(server.uri().to_owned(), bucket);
+    }
+
+    /// Removes the rate limit set on a server.
+    pub fn remove_rate_limit(&mut self, server: &DnsHttpsServer) {
+        self.rate_limits.remove(server.uri());
+    }
+
+    /// Establishes connections to all the servers ahead of the first query so that it does
+    /// not wait for the TCP and TLS handshakes. The NS records of the root zone are queried
+    /// from every server at once, each within its timeout. The connections are then kept
+    /// open as set with the [ClientBuilder](crate::client::ClientBuilder), where HTTP/2
+    /// pings can keep them from being closed while idle. If some servers cannot be reached,
+    /// the failed attempts are returned in a [DnsError::Request] but the other connections
+    /// are still established.
+    pub async fn warm_up(&self) -> Result<(), DnsError> {
+        // The servers of the routes are connected to as well, each only once.
+        let mut servers = self.servers.iter().collect::<Vec<_>>();
+        for route in self.routes.values() {
+            if let Route::Servers(route_servers) = route {
+                for server in route_servers {
+                    if servers.iter().all(|s| s.uri() != server.uri()) {
+                        servers.push(server);
+                    }
+                }
+            }
+        }
+        let requests = servers.into_iter().map(|server| async move {
+            let url = format!("{}?name=.&type={}", server.uri(), RTYPE_ns.1);
+            let endpoint = match url.parse::<Uri>() {
+                Err(e) => return Err(DnsError::Query(QueryError::InvalidEndpoint(e))),
+                Ok(endpoint) => endpoint,
+            };
+            let start = Instant::now();
+            let mut status = None;
+            let attempt = self.limited_request(
+                server,
+                endpoint,
+                ".",
+                &RTYPE_ns,
+                &mut status,
+                server.timeout(),
+            );
+            let error = match timeout(server.timeout(), attempt).await {
+                Ok(Ok(_)) => return Ok(None),
+                Ok(Err(e)) => e,
+                Err(e) => QueryError::Timeout(server.timeout(), e),
+            };
+            error!("warm up error on URL {}: {}", url, error);
+            Ok(Some(Attempt {
+                server: server.uri().to_string(),
+                url,
+                elapsed: start.elapsed(),
+                status,
+                error,
+            }))
+        });
+        let mut attempts = Vec::new();
+        for attempt in join_all(requests).await {
+            attempts.extend(attempt?);
+        }
+        if attempts.is_empty() {
+            Ok(())
+        } else {
+            Err(DnsError::Request(attempts))
+        }
+    }
+
+    /// Sets the maximum size in bytes of a response body. Larger responses fail with
+    /// [QueryError::ResponseTooLarge] and the query is retried on the next server. Defaults
+    /// to 64 KiB.
+    pub fn set_max_response_size(&mut self, size: usize) {
+        self.max_response_size = size;
+    }
+
+    /// Resolves many queries, each a name and a record type such as `("example.com", "MX")`,
+    /// with at most `concurrency` of them in flight at once. The results are streamed as
+    /// they complete, which may not be in the order of the queries, along with the query
+    /// they are for. Queries are only taken from the iterator as others complete, so it may
+    /// be lazy. Each query is made like with [Dns::resolve_str_type] and goes through the
+    /// same local names, blocklists, routes and servers.
+    pub fn resolve_many<'a, I, N, T>(
+        &'a self,
+        queries: I,
+        concurrency: usize,
+    ) -> impl Stream<Item = ((N, T), Result<Vec<DnsAnswer>, DnsError>)> + 'a
+    where
+        I: IntoIterator<Item = (N, T)>,
+        I::IntoIter: 'a,
+        N: AsRef<str> + 'a,
+        T: AsRef<str> + 'a,
+    {
+        stream::iter(queries)
+            .map(move |(name, rtype)| async move {
+                let res = self.resolve_str_type(name.as_ref(), rtype.as_ref()).await;
+                ((name, rtype), res)
+            })
+            .buffer_unordered(concurrency.max(1))
     }
 
     /// Returns MX records in order of priority for the given name. It removes the priorities
     /// from the data.
     pub async fn resolve_mx_and_sort(&self, domain: &str) -> Result<Vec<DnsAnswer>, DnsError> {
-        match self.client_request(domain, &RTYPE_mx).await {
-            Err(e) => Err(DnsError::Query(e)),
-            Ok(res) => match num::FromPrimitive::from_u32(res.Status) {
-                Some(RCode::NoError) => {
+        self.mx_and_sort(domain, self.deadline).await
+    }
+
+    /// Same as [Dns::resolve_mx_and_sort] but the whole query, including retries, has to
+    /// finish within the given deadline. It overrides the deadline set with
+    /// [Dns::set_deadline].
+    pub async fn resolve_mx_and_sort_with_deadline(
+        &self,
+        domain: &str,
+        deadline: Duration,
+    ) -> Result<Vec<DnsAnswer>, DnsError> {
+        self.mx_and_sort(domain, Some(deadline)).await
+    }
+
+    async fn mx_and_sort(
+        &self,
+        domain: &str,
+        deadline: Option<Duration>,
+    ) -> Result<Vec<DnsAnswer>, DnsError> {
+        match self.client_request(domain, &RTYPE_mx, deadline).await {
+            Err(e) => Err(e),
+            Ok(res) => match RCode::from(res.Status) {
+                RCode::NoError => {
                     let mut mxs = res
                         .Answer
                         .unwrap_or_else(|| vec![])
@@ -70,8 +406,7 @@ impl<C: DnsClient> Dns<C> {
                     mxs.sort_unstable_by_key(|x| x.1);
                     Ok(mxs.into_iter().map(|x| x.0).collect())
                 }
-                Some(code) => Err(DnsError::Status(code)),
-                None => Err(DnsError::Status(RCode::Unknown)),
+                code => Err(DnsError::Status(code, res.extended_errors())),
             },
         }
     }
@@ -82,11 +417,12 @@ impl<C: DnsClient> Dns<C> {
         &self,
         name: &str,
         rtype: &Rtype,
+        deadline: Option<Duration>,
     ) -> Result<Vec<DnsAnswer>, DnsError> {
-        match self.client_request(name, rtype).await {
-            Err(e) => Err(DnsError::Query(e)),
-            Ok(res) => match num::FromPrimitive::from_u32(res.Status) {
-                Some(RCode::NoError) => Ok(res
+        match self.client_request(name, rtype, deadline).await {
+            Err(e) => Err(e),
+            Ok(res) => match RCode::from(res.Status) {
+                RCode::NoError => Ok(res
                     .Answer
                     .unwrap_or_else(|| vec![])
                     .into_iter()
@@ -94,64 +430,516 @@ impl<C: DnsClient> Dns<C> {
                     // the ANY record which has a value of 0.
                     .filter(|a| a.r#type == rtype.0 || rtype.0 == 0)
                     .collect::<Vec<_>>()),
-                Some(code) => Err(DnsError::Status(code)),
-                None => Err(DnsError::Status(RCode::Unknown)),
+                code => Err(DnsError::Status(code, res.extended_errors())),
             },
         }
     }
 
+    // Queries a record type given by its number and returns the whole response whatever its
+    // status, including the records of other types such as the CNAME chain.
+    pub(crate) async fn query(&self, name: &str, rtype: u16) -> Result<DnsResponse, DnsError> {
+        match num_to_rtype(rtype) {
+            Some(rtype) => self.client_request(name, rtype, self.deadline).await,
+            None => Err(DnsError::InvalidRecordType),
+        }
+    }
+
     // Creates the HTTPS request to the server. In certain occasions, it retries to a new server
-    // if one is available.
-    async fn client_request(&self, name: &str, rtype: &Rtype) -> Result<DnsResponse, QueryError> {
+    // if one is available, or queries several of them at once in consensus mode. If a
+    // deadline is given, the time spent on all attempts is capped by it. On failure, every
+    // attempt made is returned with the error.
+    async fn client_request(
+        &self,
+        name: &str,
+        rtype: &Rtype,
+        deadline: Option<Duration>,
+    ) -> Result<DnsResponse, DnsError> {
         // Name has to be puny encoded.
         let name = match idna::domain_to_ascii(name) {
             Ok(name) => name,
-            Err(e) => return Err(QueryError::InvalidName(format!("{:?}", e))),
+            Err(e) => return Err(DnsError::Query(QueryError::InvalidName(format!("{:?}", e)))),
+        };
+        // Local names are answered without querying the servers, unless their CNAME chain
+        // leads out of the local names. Its target is then resolved through the servers.
+        let local = match self.hosts.lookup(&name, rtype.0) {
+            Some(local) => local,
+            None => return self.remote_request(&name, rtype, deadline).await,
+        };
+        let mut answers = local.answers;
+        let mut res = match local.target {
+            Some(target) => self.remote_request(&target, rtype, deadline).await?,
+            None => DnsResponse {
+                Status: u16::from(RCode::NoError),
+                Question: None,
+                Answer: None,
+                Comment: None,
+            },
+        };
+        answers.extend(res.Answer.take().unwrap_or_default());
+        res.Answer = Some(answers);
+        Ok(res)
+    }
+
+    // Answers a name that is not local with its servers, unless it is blocked or refused.
+    // The name is puny encoded.
+    async fn remote_request(
+        &self,
+        name: &str,
+        rtype: &Rtype,
+        deadline: Option<Duration>,
+    ) -> Result<DnsResponse, DnsError> {
+        if self.blocklist.is_blocked(name) {
+            return Ok(self.blocklist.blocked_response(name, rtype.0));
+        }
+        let servers = match self.servers_for(name) {
+            Some(servers) => servers,
+            None => return Err(DnsError::Status(RCode::Refused, vec![])),
+        };
+        let mut res = match self.consensus {
+            Some(count) => {
+                let count = count.clamp(1, servers.len());
+                self.consensus_request(name, rtype, &servers[..count], deadline)
+                    .await?
+            }
+            None => self.servers_request(name, rtype, servers, deadline).await?,
         };
-        let mut error = QueryError::Unknown;
-        for server in self.servers.iter() {
+        // Records that are not of the name or its CNAME chain are dropped.
+        if let Some(answers) = res.Answer.take() {
+            let answers = resolution_chain(name, answers);
+            res.Answer = Some(self.rebinding.filter(name, answers)?);
+        }
+        Ok(res)
+    }
+
+    // Tries the servers in order until one of them responds or fails with an error that is
+    // not retried.
+    async fn servers_request(
+        &self,
+        name: &str,
+        rtype: &Rtype,
+        servers: &[DnsHttpsServer],
+        deadline: Option<Duration>,
+    ) -> Result<DnsResponse, DnsError> {
+        let start = Instant::now();
+        let mut attempts = Vec::with_capacity(servers.len());
+        for server in servers.iter() {
             let url = format!("{}?name={}&type={}", server.uri(), name, rtype.1);
             let endpoint = match url.parse::<Uri>() {
-                Err(e) => return Err(QueryError::InvalidEndpoint(e.to_string())),
+                Err(e) => return Err(DnsError::Query(QueryError::InvalidEndpoint(e))),
                 Ok(endpoint) => endpoint,
             };
 
-            error = match timeout(server.timeout(), self.client.get(endpoint)).await {
-                Ok(Err(e)) => QueryError::Connection(e.to_string()),
-                Ok(Ok(res)) => {
-                    match res.status().as_u16() {
-                        200 => match hyper::body::to_bytes(res).await {
-                            Err(e) => QueryError::ReadResponse(e.to_string()),
-                            Ok(body) => match serde_json::from_slice::<DnsResponse>(&body) {
-                                Err(e) => QueryError::ParseResponse(e.to_string()),
-                                Ok(res) => {
-                                    return Ok(res);
-                                }
-                            },
-                        },
-                        400 => return Err(QueryError::BadRequest400),
-                        413 => return Err(QueryError::PayloadTooLarge413),
-                        414 => return Err(QueryError::UriTooLong414),
-                        415 => return Err(QueryError::UnsupportedMediaType415),
-                        501 => return Err(QueryError::NotImplemented501),
-                        // If the following errors occur, the request will be retried on
-                        // the next server if one is available.
-                        429 => QueryError::TooManyRequests429,
-                        500 => QueryError::InternalServerError500,
-                        502 => QueryError::BadGateway502,
-                        504 => QueryError::ResolverTimeout504,
-                        _ => QueryError::Unknown,
-                    }
+            // The attempt is given whatever is left of the deadline if that is shorter than
+            // the server timeout.
+            let mut attempt_timeout = server.timeout();
+            let mut limited_by_deadline = false;
+            if let Some(deadline) = deadline {
+                let remaining = deadline.checked_sub(start.elapsed()).unwrap_or_default();
+                if remaining == Duration::from_secs(0) {
+                    return Err(DnsError::DeadlineExceeded(deadline, attempts));
                 }
-                Err(_) => QueryError::Connection(format!(
-                    "connection timeout after {:?}",
-                    server.timeout()
-                )),
+                if remaining < attempt_timeout {
+                    attempt_timeout = remaining;
+                    limited_by_deadline = true;
+                }
+            }
+
+            let attempt_start = Instant::now();
+            let mut status = None;
+            let attempt =
+                self.limited_request(server, endpoint, name, rtype, &mut status, attempt_timeout);
+            let error = match timeout(attempt_timeout, attempt).await {
+                Ok(Ok(res)) => return Ok(res),
+                Ok(Err(e)) => e,
+                Err(_) if limited_by_deadline => {
+                    QueryError::DeadlineExceeded(deadline.unwrap_or_default())
+                }
+                Err(e) => QueryError::Timeout(attempt_timeout, e),
+            };
+            // Only some errors are retried on the next server.
+            let retry = error.is_retryable();
+            let exceeded = matches!(error, QueryError::DeadlineExceeded(_));
+            error!("request error on URL {}: {}", url, error);
+            attempts.push(Attempt {
+                server: server.uri().to_string(),
+                url,
+                elapsed: attempt_start.elapsed(),
+                status,
+                error,
+            });
+            if exceeded {
+                return Err(DnsError::DeadlineExceeded(
+                    deadline.unwrap_or_default(),
+                    attempts,
+                ));
+            }
+            if !retry {
+                break;
+            }
+        }
+        Err(DnsError::Request(attempts))
+    }
+
+    // Queries all the servers at once and returns the response given by most of them. If
+    // every request fails, the attempts are returned like when the servers are tried in order.
+    async fn consensus_request(
+        &self,
+        name: &str,
+        rtype: &Rtype,
+        servers: &[DnsHttpsServer],
+        deadline: Option<Duration>,
+    ) -> Result<DnsResponse, DnsError> {
+        let requests = servers.iter().map(|server| async move {
+            let url = format!("{}?name={}&type={}", server.uri(), name, rtype.1);
+            let endpoint = match url.parse::<Uri>() {
+                Err(e) => return Err(DnsError::Query(QueryError::InvalidEndpoint(e))),
+                Ok(endpoint) => endpoint,
+            };
+            let mut attempt_timeout = server.timeout();
+            let mut limited_by_deadline = false;
+            if let Some(deadline) = deadline {
+                if deadline < attempt_timeout {
+                    attempt_timeout = deadline;
+                    limited_by_deadline = true;
+                }
+            }
+            let start = Instant::now();
+            let mut status = None;
+            let attempt =
+                self.limited_request(server, endpoint, name, rtype, &mut status, attempt_timeout);
+            let error = match timeout(attempt_timeout, attempt).await {
+                Ok(Ok(res)) => return Ok(Ok(res)),
+                Ok(Err(e)) => e,
+                Err(_) if limited_by_deadline => {
+                    QueryError::DeadlineExceeded(deadline.unwrap_or_default())
+                }
+                Err(e) => QueryError::Timeout(attempt_timeout, e),
             };
             error!("request error on URL {}: {}", url, error);
+            Ok(Err(Attempt {
+                server: server.uri().to_string(),
+                url,
+                elapsed: start.elapsed(),
+                status,
+                error,
+            }))
+        });
+        let mut results = Vec::with_capacity(servers.len());
+        for result in join_all(requests).await {
+            results.push(result?);
+        }
+        if results.iter().all(Result::is_err) {
+            let attempts = results
+                .into_iter()
+                .filter_map(Result::err)
+                .collect::<Vec<_>>();
+            let exceeded = attempts
+                .iter()
+                .any(|a| matches!(a.error, QueryError::DeadlineExceeded(_)));
+            return Err(match deadline {
+                Some(deadline) if exceeded => DnsError::DeadlineExceeded(deadline, attempts),
+                _ => DnsError::Request(attempts),
+            });
+        }
+        let keys = results
+            .iter()
+            .map(|r| r.as_ref().ok().map(|res| consensus_key(name, res)))
+            .collect::<Vec<_>>();
+        let majority = keys.iter().position(|key| {
+            key.is_some() && keys.iter().filter(|k| *k == key).count() * 2 > keys.len()
+        });
+        if let Some(Ok(res)) = majority.map(|i| results.swap_remove(i)) {
+            return Ok(res);
+        }
+        let answers = results
+            .into_iter()
+            .zip(servers)
+            .map(|(result, server)| ServerAnswer {
+                server: server.uri().to_string(),
+                answer: match result {
+                    Ok(res) => Ok((RCode::from(res.Status), res.Answer.unwrap_or_default())),
+                    Err(attempt) => Err(attempt.error),
+                },
+            })
+            .collect();
+        Err(DnsError::Disagreement(answers))
+    }
+
+    // Makes a request to a server once its rate limit, if any, allows it within the timeout
+    // of the attempt.
+    async fn limited_request(
+        &self,
+        server: &DnsHttpsServer,
+        endpoint: Uri,
+        name: &str,
+        rtype: &Rtype,
+        status: &mut Option<u16>,
+        attempt_timeout: Duration,
+    ) -> Result<DnsResponse, QueryError> {
+        if let Some(bucket) = self.rate_limits.get(server.uri()) {
+            bucket.acquire(attempt_timeout).await?;
+        }
+        self.server_request(endpoint, name, rtype, status).await
+    }
+
+    // Makes a single request to a server and parses its response, which has to be for the
+    // name and type queried. The HTTP status is set once a response is received.
+    async fn server_request(
+        &self,
+        endpoint: Uri,
+        name: &str,
+        rtype: &Rtype,
+        status: &mut Option<u16>,
+    ) -> Result<DnsResponse, QueryError> {
+        let mut endpoint = endpoint;
+        let mut redirects = 0;
+        let res = loop {
+            let res = match self.client.get(endpoint.clone()).await {
+                Err(e) if tls::is_pin_mismatch(&e) => return Err(QueryError::PinMismatch(e)),
+                Err(e) => return Err(QueryError::Connection(e)),
+                Ok(res) => res,
+            };
+            *status = Some(res.status().as_u16());
+            if !is_redirect(res.status()) {
+                break res;
+            }
+            if redirects == MAX_REDIRECTS {
+                return Err(QueryError::TooManyRedirects(MAX_REDIRECTS));
+            }
+            redirects += 1;
+            let next = redirect_uri(&endpoint, &res)?;
+            if !self.client.redirect_allowed(&endpoint, &next) {
+                return Err(QueryError::InvalidRedirect(Some(next.to_string())));
+            }
+            endpoint = next;
+        };
+        match res.status().as_u16() {
+            200 => {
+                check_content_type(&res)?;
+                let lifetime = cache_lifetime(res.headers());
+                let body = read_body(res, self.max_response_size).await?;
+                let mut response = serde_json::from_slice::<DnsResponse>(&body)
+                    .map_err(QueryError::ParseResponse)?;
+                check_question(&response, name, rtype)?;
+                // The records are not kept longer than the HTTP response may be cached.
+                if let (Some(lifetime), Some(answers)) = (lifetime, response.Answer.as_mut()) {
+                    for answer in answers.iter_mut() {
+                        answer.TTL = answer.TTL.min(lifetime);
+                    }
+                }
+                Ok(response)
+            }
+            400 => Err(QueryError::BadRequest400),
+            413 => Err(QueryError::PayloadTooLarge413),
+            414 => Err(QueryError::UriTooLong414),
+            415 => Err(QueryError::UnsupportedMediaType415),
+            501 => Err(QueryError::NotImplemented501),
+            429 => Err(QueryError::TooManyRequests429),
+            500 => Err(QueryError::InternalServerError500),
+            502 => Err(QueryError::BadGateway502),
+            504 => Err(QueryError::ResolverTimeout504),
+            _ => Err(QueryError::Unknown),
+        }
+    }
+}
+
+// Checks that the question of a response, if it is given, is the one asked. The type of
+// `ANY` is given as 255 in responses.
+fn check_question(res: &DnsResponse, name: &str, rtype: &Rtype) -> Result<(), QueryError> {
+    let expected = if rtype.0 == RTYPE_any.0 { 255 } else { rtype.0 };
+    for question in res.Question.iter().flatten() {
+        if name_key(&question.name) != name_key(name) || question.r#type != expected {
+            return Err(QueryError::QuestionMismatch(
+                question.name.clone(),
+                question.r#type,
+            ));
         }
-        Err(error)
     }
+    Ok(())
+}
+
+// Keeps the records of the name queried and of the names of its CNAME chain, along with the
+// DNAME records of their parents, dropping the records of unrelated names.
+fn resolution_chain(name: &str, answers: Vec<DnsAnswer>) -> Vec<DnsAnswer> {
+    let mut names = vec![name_key(name)];
+    // The CNAME records may be given in any order so they are followed until no new name
+    // is found.
+    loop {
+        let next = answers
+            .iter()
+            .filter(|a| a.r#type == RTYPE_cname.0 && names.contains(&name_key(&a.name)))
+            .map(|a| name_key(&a.data))
+            .find(|target| !names.contains(target));
+        match next {
+            Some(target) => names.push(target),
+            None => break,
+        }
+    }
+    answers
+        .into_iter()
+        .filter(|a| {
+            let owner = name_key(&a.name);
+            names.contains(&owner)
+                || (a.r#type == RTYPE_dname.0
+                    && names.iter().any(|n| n.ends_with(&format!(".{}", owner))))
+        })
+        .collect()
+}
+
+// Returns a name in lowercase without the trailing dot to compare it with others.
+fn name_key(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+// Returns what is compared between the responses of the servers in consensus mode: the
+// status and the records of the name and its CNAME chain without their TTL, sorted and
+// deduplicated.
+fn consensus_key(name: &str, res: &DnsResponse) -> (u16, Vec<(String, u32, RecordData)>) {
+    let answers = resolution_chain(name, res.Answer.clone().unwrap_or_default());
+    let mut records = answers
+        .iter()
+        .map(|a| (name_key(&a.name), a.r#type, record_data(a.r#type, &a.data)))
+        .collect::<Vec<_>>();
+    records.sort_unstable();
+    records.dedup();
+    (res.Status, records)
+}
+
+// The data of a record, in wire format when it is supported, or its text otherwise.
+type RecordData = Result<Vec<u8>, String>;
+
+// Parses the data of a record so that it does not depend on how each provider formats it,
+// such as Cloudflare quoting TXT strings while Google does not. The character strings of
+// TXT records are joined since they may be split differently.
+fn record_data(rtype: u32, data: &str) -> RecordData {
+    let parsed = if rtype == RTYPE_txt.0 || rtype == RTYPE_spf.0 {
+        wire::character_strings(data.trim()).map(|strings| strings.concat())
+    } else {
+        wire::rdata_from_text(rtype as u16, data)
+    };
+    parsed.map_err(|_| data.to_owned())
+}
+
+// Normalizes the domain of a route to the lowercase puny encoded form of the names queried.
+fn route_domain(domain: &str) -> Result<String, DnsError> {
+    let domain = domain.trim_start_matches("*.").trim_end_matches('.');
+    match idna::domain_to_ascii(domain) {
+        Ok(domain) if !domain.is_empty() => Ok(domain.to_ascii_lowercase()),
+        Ok(_) => Err(DnsError::Query(QueryError::InvalidName(
+            "empty route domain".to_owned(),
+        ))),
+        Err(e) => Err(DnsError::Query(QueryError::InvalidName(format!("{:?}", e)))),
+    }
+}
+
+// Returns the number of a record type given by its case insensitive name or its number, as
+// used in a message. The number has to be of a type that can be queried.
+pub(crate) fn rtype_number(rtype: &str) -> Option<u16> {
+    match str_to_rtype(rtype) {
+        Some(rtype) if rtype.0 == RTYPE_any.0 => Some(255),
+        Some(rtype) => Some(rtype.0 as u16),
+        None => rtype
+            .parse::<u16>()
+            .ok()
+            .filter(|n| num_to_rtype(*n).is_some()),
+    }
+}
+
+fn is_redirect(status: StatusCode) -> bool {
+    matches!(status.as_u16(), 301 | 302 | 303 | 307 | 308)
+}
+
+// Returns the URI a redirect points to, resolving a location given as an absolute path
+// against the URI redirected. Only redirects to the same scheme are followed so that a
+// query is never downgraded to plain HTTP.
+fn redirect_uri(from: &Uri, res: &Response<Body>) -> Result<Uri, QueryError> {
+    let location = match res.headers().get(LOCATION) {
+        Some(value) => String::from_utf8_lossy(value.as_bytes()).into_owned(),
+        None => return Err(QueryError::InvalidRedirect(None)),
+    };
+    let uri = if location.starts_with('/') && !location.starts_with("//") {
+        format!(
+            "{}://{}{}",
+            from.scheme_str().unwrap_or_default(),
+            from.authority().map(|a| a.as_str()).unwrap_or_default(),
+            location
+        )
+        .parse::<Uri>()
+    } else {
+        location.parse::<Uri>()
+    };
+    match uri {
+        Ok(uri)
+            if uri.scheme().is_some() && uri.scheme() == from.scheme() && uri.host().is_some() =>
+        {
+            Ok(uri)
+        }
+        _ => Err(QueryError::InvalidRedirect(Some(location))),
+    }
+}
+
+// Returns how many seconds the response may still be cached, which is its
+// `Cache-Control: max-age` less its `Age`, as RFC 8484 ties the TTLs to it.
+fn cache_lifetime(headers: &HeaderMap) -> Option<u32> {
+    let max_age = headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|directive| {
+            let (name, value) = directive.split_once('=')?;
+            if !name.trim().eq_ignore_ascii_case("max-age") {
+                return None;
+            }
+            value.trim().trim_matches('"').parse::<u64>().ok()
+        })
+        .min()?;
+    let age = headers
+        .get(AGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .unwrap_or_default();
+    Some(max_age.saturating_sub(age).min(u32::MAX as u64) as u32)
+}
+
+// Checks that the response is JSON. Cloudflare answers with `application/dns-json` while
+// Google answers with `application/json`.
+fn check_content_type(res: &Response<Body>) -> Result<(), QueryError> {
+    let content_type = match res.headers().get(CONTENT_TYPE) {
+        Some(value) => String::from_utf8_lossy(value.as_bytes()).into_owned(),
+        None => return Err(QueryError::InvalidContentType(None)),
+    };
+    let media_type = content_type.split(';').next().unwrap_or_default().trim();
+    if JSON_CONTENT_TYPES
+        .iter()
+        .any(|t| t.eq_ignore_ascii_case(media_type))
+    {
+        Ok(())
+    } else {
+        Err(QueryError::InvalidContentType(Some(content_type)))
+    }
+}
+
+// Reads the body of a response, failing as soon as it is larger than the maximum size.
+async fn read_body(res: Response<Body>, max_size: usize) -> Result<Vec<u8>, QueryError> {
+    let content_length = res
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if content_length.is_some_and(|len| len > max_size) {
+        return Err(QueryError::ResponseTooLarge(max_size));
+    }
+    let mut body = res.into_body();
+    let mut data = Vec::with_capacity(content_length.unwrap_or_default());
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(QueryError::ReadResponse)?;
+        if data.len() + chunk.len() > max_size {
+            return Err(QueryError::ResponseTooLarge(max_size));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
 }
 
 struct Rtype(pub u32, pub &'static str);
@@ -168,16 +956,42 @@ macro_rules! rtypes {
                 $(
                     $(#[$docs])*
                     pub async fn [<resolve_ $konst>](&self, name: &str) -> Result<Vec<DnsAnswer>, DnsError> {
-                        self.request_and_process(name, &[<RTYPE_ $konst>]).await
+                        self.request_and_process(name, &[<RTYPE_ $konst>], self.deadline).await
+                    }
+
+                    #[doc = concat!(
+                        "Same as [Dns::resolve_", stringify!($konst), "] but the whole query, ",
+                        "including retries, has to finish within the given deadline. It ",
+                        "overrides the deadline set with [Dns::set_deadline]."
+                    )]
+                    pub async fn [<resolve_ $konst _with_deadline>](
+                        &self,
+                        name: &str,
+                        deadline: Duration,
+                    ) -> Result<Vec<DnsAnswer>, DnsError> {
+                        self.request_and_process(name, &[<RTYPE_ $konst>], Some(deadline)).await
                     }
                 )+
 
                 pub async fn resolve_str_type(&self, name: &str, rtype: &str) -> Result<Vec<DnsAnswer>, DnsError> {
-                    match rtype.to_ascii_lowercase().as_ref() {
-                        $(
-                        stringify!($konst) => self.[<resolve_ $konst>](name).await,
-                        )+
-                        _ => Err(DnsError::InvalidRecordType),
+                    match str_to_rtype(rtype) {
+                        Some(rtype) => self.request_and_process(name, rtype, self.deadline).await,
+                        None => Err(DnsError::InvalidRecordType),
+                    }
+                }
+
+                /// Same as [Dns::resolve_str_type] but the whole query, including retries, has
+                /// to finish within the given deadline. It overrides the deadline set with
+                /// [Dns::set_deadline].
+                pub async fn resolve_str_type_with_deadline(
+                    &self,
+                    name: &str,
+                    rtype: &str,
+                    deadline: Duration,
+                ) -> Result<Vec<DnsAnswer>, DnsError> {
+                    match str_to_rtype(rtype) {
+                        Some(rtype) => self.request_and_process(name, rtype, Some(deadline)).await,
+                        None => Err(DnsError::InvalidRecordType),
                     }
                 }
 
@@ -196,6 +1010,28 @@ macro_rules! rtypes {
             #[allow(non_upper_case_globals)]
             const [<RTYPE_ $konst>]: Rtype = Rtype($num, stringify!($konst));
         )+
+
+            // Finds the record type by its number as found in a message. `ANY` is 255 in a
+            // message.
+            fn num_to_rtype(rtype: u16) -> Option<&'static Rtype> {
+                match rtype as u32 {
+                    255 => Some(&RTYPE_any),
+                    $(
+                    $num => Some(&[<RTYPE_ $konst>]),
+                    )+
+                    _ => None,
+                }
+            }
+
+            // Finds the record type by its case insensitive name.
+            fn str_to_rtype(rtype: &str) -> Option<&'static Rtype> {
+                match rtype.to_ascii_lowercase().as_ref() {
+                    $(
+                    stringify!($konst) => Some(&[<RTYPE_ $konst>]),
+                    )+
+                    _ => None,
+                }
+            }
         }
     }
 }
@@ -266,49 +1102,112 @@ pub mod tests {
     use async_trait::async_trait;
     use hyper::StatusCode;
     use hyper::{error::Result as HyperResult, Body, Response, Uri};
+    use std::error::Error;
     use std::sync::{
         atomic::{AtomicUsize, Ordering},
-        Arc,
+        Arc, Mutex,
     };
-    struct MockDnsClient {
+    pub(crate) struct MockDnsClient {
         response: Vec<(String, StatusCode)>,
         counter: Arc<AtomicUsize>,
+        delay: Duration,
+        content_type: &'static str,
+        headers: Vec<(usize, &'static str, &'static str)>,
+        uris: Arc<Mutex<Vec<Uri>>>,
+        configured_hosts: Vec<&'static str>,
     }
 
     impl MockDnsClient {
-        fn new(response: &[(String, StatusCode)]) -> MockDnsClient {
+        pub(crate) fn new(response: &[(String, StatusCode)]) -> MockDnsClient {
+            MockDnsClient::with_delay(response, Duration::from_secs(0))
+        }
+
+        // Each response is returned after the given delay.
+        pub(crate) fn with_delay(
+            response: &[(String, StatusCode)],
+            delay: Duration,
+        ) -> MockDnsClient {
             MockDnsClient {
                 response: response.to_vec(),
                 counter: Arc::new(AtomicUsize::new(0)),
+                delay,
+                content_type: "application/dns-json",
+                headers: vec![],
+                uris: Arc::new(Mutex::new(vec![])),
+                configured_hosts: vec![],
+            }
+        }
+
+        // Gives the host its own options so that it is not redirected to other hosts.
+        fn with_options_for(mut self, host: &'static str) -> MockDnsClient {
+            self.configured_hosts.push(host);
+            self
+        }
+
+        // Adds a header to the response of the given index.
+        fn with_header(
+            mut self,
+            index: usize,
+            name: &'static str,
+            value: &'static str,
+        ) -> MockDnsClient {
+            self.headers.push((index, name, value));
+            self
+        }
+
+        // Each response is returned with the given content type.
+        fn with_content_type(
+            response: &[(String, StatusCode)],
+            content_type: &'static str,
+        ) -> MockDnsClient {
+            MockDnsClient {
+                content_type,
+                ..MockDnsClient::new(response)
             }
         }
     }
 
     #[async_trait]
     impl DnsClient for MockDnsClient {
-        async fn get(&self, _uri: Uri) -> HyperResult<Response<Body>> {
+        async fn get(&self, uri: Uri) -> HyperResult<Response<Body>> {
             let counter = Arc::clone(&self.counter);
             let index = counter.fetch_add(1, Ordering::SeqCst);
+            self.uris.lock().unwrap().push(uri);
+            tokio::time::delay_for(self.delay).await;
             // If more calls than results are given, an out of bounds error should be obtained.
             let chunks: Vec<Result<_, ::std::io::Error>> = vec![Ok(self.response[index].0.clone())];
             let stream = futures_util::stream::iter(chunks);
             let body = Body::wrap_stream(stream);
             let mut response = Response::new(body);
             *response.status_mut() = self.response[index].1;
+            response.headers_mut().insert(
+                hyper::header::CONTENT_TYPE,
+                hyper::header::HeaderValue::from_static(self.content_type),
+            );
+            for (_, name, value) in self.headers.iter().filter(|h| h.0 == index) {
+                response
+                    .headers_mut()
+                    .append(*name, hyper::header::HeaderValue::from_static(value));
+            }
             Ok(response)
         }
+
+        fn redirect_allowed(&self, from: &Uri, to: &Uri) -> bool {
+            from.host() == to.host()
+                || from
+                    .host()
+                    .is_none_or(|host| !self.configured_hosts.contains(&host))
+        }
     }
 
     impl Default for MockDnsClient {
         fn default() -> MockDnsClient {
-            MockDnsClient {
-                response: vec![],
-                counter: Arc::new(AtomicUsize::new(0)),
-            }
+            MockDnsClient::new(&[])
         }
     }
 
     use super::*;
+    use crate::status::{ExtendedError, ExtendedErrorCode};
 
     #[tokio::test]
     async fn test_a() {
@@ -362,11 +1261,11 @@ pub mod tests {
   "Comment": "Response from 2600:1801:13::1."
     }"#,
         );
-        let d = Dns {
-            client: MockDnsClient::new(&[(response, StatusCode::OK)]),
-            servers: vec![DnsHttpsServer::Google(Duration::from_secs(5))],
-        };
-        let r = d.resolve_a("sendgrid.com").await.unwrap();
+        let d = Dns::new(
+            MockDnsClient::new(&[(response, StatusCode::OK)]),
+            vec![DnsHttpsServer::Google(Duration::from_secs(5))],
+        );
+        let r = d.resolve_a("www.sendgrid.com").await.unwrap();
         assert_eq!(r.len(), 4);
         assert_eq!(r[0].name, "sendgrid.com.");
         assert_eq!(r[0].data, "169.45.113.198");
@@ -438,10 +1337,10 @@ pub mod tests {
   "Comment": "Response from 2001:4860:4802:32::a."
 }"#,
         );
-        let d = Dns {
-            client: MockDnsClient::new(&[(response.clone(), StatusCode::OK)]),
-            servers: vec![DnsHttpsServer::Google(Duration::from_secs(5))],
-        };
+        let d = Dns::new(
+            MockDnsClient::new(&[(response.clone(), StatusCode::OK)]),
+            vec![DnsHttpsServer::Google(Duration::from_secs(5))],
+        );
         let r = d.resolve_mx_and_sort("gmail.com").await.unwrap();
         assert_eq!(r.len(), 5);
         assert_eq!(r[0].name, "gmail.com.");
@@ -465,10 +1364,10 @@ pub mod tests {
         assert_eq!(r[4].r#type, 15);
         assert_eq!(r[4].TTL, 3599);
 
-        let d = Dns {
-            client: MockDnsClient::new(&[(response, StatusCode::OK)]),
-            servers: vec![DnsHttpsServer::Google(Duration::from_secs(5))],
-        };
+        let d = Dns::new(
+            MockDnsClient::new(&[(response, StatusCode::OK)]),
+            vec![DnsHttpsServer::Google(Duration::from_secs(5))],
+        );
         let r = d.resolve_mx("gmail.com").await.unwrap();
         assert_eq!(r.len(), 5);
         assert_eq!(r[0].name, "gmail.com.");
@@ -545,10 +1444,10 @@ pub mod tests {
   "Comment": "Response from 216.239.36.10."
 }"#,
         );
-        let d = Dns {
-            client: MockDnsClient::new(&[(response, StatusCode::OK)]),
-            servers: vec![DnsHttpsServer::Google(Duration::from_secs(5))],
-        };
+        let d = Dns::new(
+            MockDnsClient::new(&[(response, StatusCode::OK)]),
+            vec![DnsHttpsServer::Google(Duration::from_secs(5))],
+        );
         let r = d.resolve_txt("google.com").await.unwrap();
         assert_eq!(r.len(), 5);
         assert_eq!(r[0].name, "google.com.");
@@ -613,16 +1512,16 @@ pub mod tests {
 }"#,
         );
         // Retry if more than server is given.
-        let d = Dns {
-            client: MockDnsClient::new(&[
+        let d = Dns::new(
+            MockDnsClient::new(&[
                 ("".to_owned(), StatusCode::INTERNAL_SERVER_ERROR),
                 (response.clone(), StatusCode::OK),
             ]),
-            servers: vec![
+            vec![
                 DnsHttpsServer::Google(Duration::from_secs(5)),
                 DnsHttpsServer::Cloudflare1_1_1_1(Duration::from_secs(5)),
             ],
-        };
+        );
         let r = d.resolve_a("www.google.com").await.unwrap();
         assert_eq!(r.len(), 1);
         assert_eq!(r[0].name, "www.google.com.");
@@ -631,28 +1530,823 @@ pub mod tests {
         assert_eq!(r[0].TTL, 163);
 
         // Not all errors should be retried.
-        let d = Dns {
-            client: MockDnsClient::new(&[
+        let d = Dns::new(
+            MockDnsClient::new(&[
                 ("".to_owned(), StatusCode::BAD_REQUEST),
                 (response.clone(), StatusCode::OK),
             ]),
-            servers: vec![
+            vec![
                 DnsHttpsServer::Google(Duration::from_secs(5)),
                 DnsHttpsServer::Cloudflare1_1_1_1(Duration::from_secs(5)),
             ],
-        };
-        let r = d.resolve_a("www.google.com").await;
-        assert!(r.is_err());
+        );
+        match d.resolve_a("www.google.com").await {
+            Err(DnsError::Request(attempts)) => {
+                assert_eq!(attempts.len(), 1);
+                assert_eq!(attempts[0].server, "https://dns.google/resolve");
+                assert_eq!(
+                    attempts[0].url,
+                    "https://dns.google/resolve?name=www.google.com&type=a"
+                );
+                assert_eq!(attempts[0].status, Some(400));
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
 
         // If only one server is given, an error should be received.
-        let d = Dns {
-            client: MockDnsClient::new(&[
+        let d = Dns::new(
+            MockDnsClient::new(&[
                 ("".to_owned(), StatusCode::INTERNAL_SERVER_ERROR),
                 (response.clone(), StatusCode::OK),
             ]),
-            servers: vec![DnsHttpsServer::Google(Duration::from_secs(5))],
-        };
+            vec![DnsHttpsServer::Google(Duration::from_secs(5))],
+        );
         let r = d.resolve_a("www.google.com").await;
         assert!(r.is_err());
     }
+
+    #[tokio::test]
+    async fn test_custom_server() {
+        let server = DnsHttpsServer::Custom(
+            "https://doh.example.com/resolve".to_owned(),
+            Duration::from_secs(5),
+        );
+        assert!(matches!(
+            Dns::with_client(MockDnsClient::default(), &[]),
+            Err(DnsError::NoServers)
+        ));
+        let d = Dns::with_client(
+            MockDnsClient::new(&[("".to_owned(), StatusCode::BAD_REQUEST)]),
+            &[server],
+        )
+        .unwrap();
+        match d.resolve_a("www.google.com").await {
+            Err(DnsError::Request(attempts)) => assert_eq!(
+                attempts[0].url,
+                "https://doh.example.com/resolve?name=www.google.com&type=a"
+            ),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_warm_up() {
+        let servers = vec![
+            DnsHttpsServer::Google(Duration::from_secs(5)),
+            DnsHttpsServer::Cloudflare1_1_1_1(Duration::from_secs(5)),
+        ];
+        let d = Dns::new(
+            MockDnsClient::new(&[
+                (r#"{"Status": 0}"#.to_owned(), StatusCode::OK),
+                (r#"{"Status": 0}"#.to_owned(), StatusCode::OK),
+            ]),
+            servers.clone(),
+        );
+        d.warm_up().await.unwrap();
+
+        // Only the servers that failed are returned.
+        let d = Dns::new(
+            MockDnsClient::new(&[
+                (r#"{"Status": 0}"#.to_owned(), StatusCode::OK),
+                ("".to_owned(), StatusCode::BAD_GATEWAY),
+            ]),
+            servers,
+        );
+        match d.warm_up().await {
+            Err(DnsError::Request(attempts)) => {
+                assert_eq!(attempts.len(), 1);
+                assert_eq!(attempts[0].url, "https://1.1.1.1/dns-query?name=.&type=ns");
+                assert_eq!(attempts[0].status, Some(502));
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_response_validation() {
+        let servers = vec![DnsHttpsServer::Google(Duration::from_secs(5))];
+        let response = r#"{"Status": 0, "Answer": []}"#.to_owned();
+
+        // Google's content type is accepted.
+        let d = Dns::new(
+            MockDnsClient::with_content_type(
+                &[(response.clone(), StatusCode::OK)],
+                "application/json; charset=UTF-8",
+            ),
+            servers.clone(),
+        );
+        assert!(d.resolve_a("www.google.com").await.is_ok());
+
+        // A captive portal page is rejected before being parsed.
+        let d = Dns::new(
+            MockDnsClient::with_content_type(
+                &[("<html></html>".to_owned(), StatusCode::OK)],
+                "text/html",
+            ),
+            servers.clone(),
+        );
+        let e = d.resolve_a("www.google.com").await.unwrap_err();
+        assert!(e.is_retryable());
+        match e {
+            DnsError::Request(attempts) => match attempts[0].error {
+                QueryError::InvalidContentType(Some(ref t)) => assert_eq!(t, "text/html"),
+                ref e => panic!("unexpected error: {:?}", e),
+            },
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        let mut d = Dns::new(MockDnsClient::new(&[(response, StatusCode::OK)]), servers);
+        d.set_max_response_size(16);
+        match d.resolve_a("www.google.com").await {
+            Err(DnsError::Request(attempts)) => match attempts[0].error {
+                QueryError::ResponseTooLarge(16) => {}
+                ref e => panic!("unexpected error: {:?}", e),
+            },
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_redirects() {
+        let servers = vec![DnsHttpsServer::Custom(
+            "https://doh.example.com/resolve".to_owned(),
+            Duration::from_secs(5),
+        )];
+        let response = r#"{"Status": 0, "Answer": [
+          {"name": "example.com.", "type": 1, "TTL": 300, "data": "93.184.216.34"}
+        ]}"#
+        .to_owned();
+        let c = MockDnsClient::new(&[
+            (String::new(), StatusCode::MOVED_PERMANENTLY),
+            (String::new(), StatusCode::TEMPORARY_REDIRECT),
+            (response.clone(), StatusCode::OK),
+        ])
+        .with_header(
+            0,
+            "location",
+            "https://doh2.example.com/dns-query?name=example.com",
+        )
+        .with_header(1, "location", "/resolve?name=example.com&type=A")
+        .with_header(2, "cache-control", "public, max-age=120")
+        .with_header(2, "age", "20");
+        let uris = Arc::clone(&c.uris);
+        let d = Dns::new(c, servers.clone());
+        let r = d.resolve_a("example.com").await.unwrap();
+        assert_eq!(r[0].TTL, 100);
+        let uris = uris.lock().unwrap().clone();
+        assert_eq!(
+            uris[1].to_string(),
+            "https://doh2.example.com/dns-query?name=example.com"
+        );
+        assert_eq!(
+            uris[2].to_string(),
+            "https://doh2.example.com/resolve?name=example.com&type=A"
+        );
+
+        // Servers with their own options are not redirected to other hosts.
+        let c = MockDnsClient::new(&[(String::new(), StatusCode::FOUND)])
+            .with_header(0, "location", "https://doh2.example.com/resolve")
+            .with_options_for("doh.example.com");
+        let d = Dns::new(c, servers.clone());
+        match d.resolve_a("example.com").await {
+            Err(DnsError::Request(attempts)) => match attempts[0].error {
+                QueryError::InvalidRedirect(Some(ref l)) => {
+                    assert_eq!(l, "https://doh2.example.com/resolve")
+                }
+                ref e => panic!("unexpected error: {:?}", e),
+            },
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        // A downgrade to plain HTTP is not followed.
+        let c = MockDnsClient::new(&[(String::new(), StatusCode::FOUND)]).with_header(
+            0,
+            "location",
+            "http://doh.example.com/resolve",
+        );
+        let d = Dns::new(c, servers.clone());
+        match d.resolve_a("example.com").await {
+            Err(DnsError::Request(attempts)) => match attempts[0].error {
+                QueryError::InvalidRedirect(Some(ref l)) => {
+                    assert_eq!(l, "http://doh.example.com/resolve")
+                }
+                ref e => panic!("unexpected error: {:?}", e),
+            },
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        let mut c = MockDnsClient::new(&vec![(String::new(), StatusCode::FOUND); 6]);
+        for i in 0..6 {
+            c = c.with_header(i, "location", "/resolve");
+        }
+        let d = Dns::new(c, servers);
+        match d.resolve_a("example.com").await {
+            Err(DnsError::Request(attempts)) => match attempts[0].error {
+                QueryError::TooManyRedirects(5) => {}
+                ref e => panic!("unexpected error: {:?}", e),
+            },
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        // TTLs are kept when there is no max-age.
+        let d = Dns::new(
+            MockDnsClient::new(&[(response, StatusCode::OK)]).with_header(
+                0,
+                "cache-control",
+                "no-transform",
+            ),
+            vec![DnsHttpsServer::Google(Duration::from_secs(5))],
+        );
+        assert_eq!(d.resolve_a("example.com").await.unwrap()[0].TTL, 300);
+    }
+
+    #[tokio::test]
+    async fn test_routes() {
+        let response = r#"{"Status": 0, "Answer": []}"#.to_owned();
+        let c = MockDnsClient::new(&vec![(response, StatusCode::OK); 3]);
+        let uris = Arc::clone(&c.uris);
+        let mut d = Dns::new(c, vec![DnsHttpsServer::Google(Duration::from_secs(5))]);
+        d.set_route(
+            "*.corp.example",
+            Route::Servers(vec![DnsHttpsServer::Custom(
+                "https://doh.corp.example/resolve".to_owned(),
+                Duration::from_secs(1),
+            )]),
+        )
+        .unwrap();
+        d.set_route("example.", Route::Refuse).unwrap();
+        d.set_route("onion", Route::Refuse).unwrap();
+        assert!(d.set_route("corp.example", Route::Servers(vec![])).is_err());
+        assert!(d.set_route("*.", Route::Refuse).is_err());
+
+        // The longest suffix wins and a domain matches its own name.
+        d.resolve_a("WWW.Corp.Example.").await.unwrap();
+        d.resolve_a("corp.example").await.unwrap();
+        d.resolve_a("example.com").await.unwrap();
+        for name in &["example", "www.example", "abc.onion"] {
+            match d.resolve_a(name).await {
+                Err(DnsError::Status(RCode::Refused, _)) => {}
+                r => panic!("unexpected result for {}: {:?}", name, r),
+            }
+        }
+        let hosts = uris
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|u| u.host().unwrap().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            hosts,
+            vec!["doh.corp.example", "doh.corp.example", "dns.google"]
+        );
+
+        d.remove_route("*.example").unwrap();
+        assert_eq!(
+            d.servers_for("www.example").unwrap()[0].uri(),
+            "https://dns.google/resolve"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hosts() {
+        let mut d = Dns::new(
+            MockDnsClient::new(&[]),
+            vec![DnsHttpsServer::Google(Duration::from_secs(5))],
+        );
+        d.load_hosts("10.0.0.5 db.internal\n");
+        d.load_zone("www.internal. 60 CNAME db.internal.\nwww.internal. TXT \"v=1\"")
+            .unwrap();
+        d.set_hosts_ttl(30);
+        d.set_route("internal", Route::Refuse).unwrap();
+
+        // The servers are never queried, even if the names are refused.
+        let r = d.resolve_a("www.internal").await.unwrap();
+        assert_eq!(r.len(), 1);
+        assert_eq!((r[0].data.as_str(), r[0].TTL), ("10.0.0.5", 30));
+        assert_eq!(
+            d.resolve_txt("www.internal").await.unwrap()[0].data,
+            "\"v=1\""
+        );
+        assert!(d.resolve_aaaa("db.internal").await.unwrap().is_empty());
+        let r = d.resolve_ptr("5.0.0.10.in-addr.arpa").await.unwrap();
+        assert_eq!(r[0].data, "db.internal.");
+        assert!(d.load_zone("bad.internal. SRV 0 0 0 .").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_hosts_remote_target() {
+        let response = r#"{"Status": 0, "Answer": [
+            {"name": "cdn.example.net.", "type": 5, "TTL": 60, "data": "edge.example.net."},
+            {"name": "edge.example.net.", "type": 1, "TTL": 60, "data": "192.0.2.7"}]}"#;
+        let c = MockDnsClient::new(&[
+            (response.to_owned(), StatusCode::OK),
+            (response.to_owned(), StatusCode::OK),
+        ]);
+        let uris = Arc::clone(&c.uris);
+        let mut d = Dns::new(c, vec![DnsHttpsServer::Google(Duration::from_secs(5))]);
+        d.load_zone("www.corp. CNAME cdn.example.net.").unwrap();
+
+        // The target of a local CNAME outside the local names is resolved by the servers.
+        let r = d.resolve_a("www.corp").await.unwrap();
+        assert_eq!(r[0].data, "192.0.2.7");
+        assert!(uris.lock().unwrap()[0]
+            .to_string()
+            .contains("name=cdn.example.net."));
+        // The local CNAME comes first in the chain given to the stub and the DoH server.
+        let res = d.query("www.corp", 1).await.unwrap();
+        let chain = res.Answer.unwrap();
+        let data = chain.iter().map(|a| a.data.as_str()).collect::<Vec<_>>();
+        assert_eq!(data, ["cdn.example.net.", "edge.example.net.", "192.0.2.7"]);
+    }
+
+    #[tokio::test]
+    async fn test_blocklist() {
+        let client = MockDnsClient::new(&[("".to_owned(), StatusCode::BAD_REQUEST)]);
+        let counter = Arc::clone(&client.counter);
+        let mut d = Dns::new(client, vec![DnsHttpsServer::Google(Duration::from_secs(5))]);
+        d.load_blocklist("||ads.example.com^\n0.0.0.0 tracker.example.com\n");
+        d.load_allowlist("ok.ads.example.com");
+        match d.resolve_a("www.ads.example.com").await {
+            Err(DnsError::Status(RCode::NXDomain, extended)) => {
+                assert_eq!(extended[0].code, ExtendedErrorCode::Blocked)
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        d.set_block_response(BlockResponse::Refused);
+        assert!(matches!(
+            d.resolve_a("tracker.example.com").await,
+            Err(DnsError::Status(RCode::Refused, _))
+        ));
+        d.set_block_response(BlockResponse::Unspecified);
+        assert_eq!(
+            d.resolve_a("ads.example.com").await.unwrap()[0].data,
+            "0.0.0.0"
+        );
+        assert_eq!(
+            d.resolve_aaaa("ads.example.com").await.unwrap()[0].data,
+            "::"
+        );
+        assert!(d.resolve_mx("ads.example.com").await.unwrap().is_empty());
+
+        // Only the allowed name is sent to the servers.
+        assert!(d.resolve_a("ok.ads.example.com").await.is_err());
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rebinding() {
+        let response = r#"{"Status": 0, "Answer": [
+          {"name": "evil.example.com.", "type": 5, "TTL": 60, "data": "meta.example.com."},
+          {"name": "meta.example.com.", "type": 1, "TTL": 60, "data": "169.254.169.254"}
+        ]}"#;
+        let mut d = Dns::new(
+            MockDnsClient::new(&vec![(response.to_owned(), StatusCode::OK); 7]),
+            vec![DnsHttpsServer::Google(Duration::from_secs(5))],
+        );
+        d.load_hosts("127.0.0.1 local.example.com");
+        assert_eq!(d.resolve_a("evil.example.com").await.unwrap().len(), 1);
+
+        d.set_rebinding_protection(true);
+        match d.resolve_a("evil.example.com").await {
+            Err(DnsError::PrivateAddress(name, ip)) => {
+                assert_eq!(name, "evil.example.com");
+                assert_eq!(ip.to_string(), "169.254.169.254");
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        // The whole chain is checked whatever the type queried, but local names are not.
+        assert!(d.resolve_cname("evil.example.com").await.is_err());
+        assert!(d.resolve_a("local.example.com").await.is_ok());
+
+        d.allow_private_answers("*.example.com").unwrap();
+        assert_eq!(d.resolve_a("evil.example.com").await.unwrap().len(), 1);
+        d.disallow_private_answers("example.com").unwrap();
+        assert!(d.resolve_a("evil.example.com").await.is_err());
+
+        // The private address is dropped and the CNAME record kept.
+        d.set_rebinding_response(RebindingResponse::Drop);
+        assert!(d.resolve_a("evil.example.com").await.unwrap().is_empty());
+        let cnames = d.resolve_cname("evil.example.com").await.unwrap();
+        assert_eq!(cnames[0].data, "meta.example.com.");
+    }
+
+    #[tokio::test]
+    async fn test_consensus() {
+        let answer = |ips: &[(&str, u32)]| {
+            let answers = ips
+                .iter()
+                .map(|(ip, ttl)| {
+                    format!(
+                        r#"{{"name": "example.com.", "type": 1, "TTL": {}, "data": "{}"}}"#,
+                        ttl, ip
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(",");
+            let response = format!(r#"{{"Status": 0, "Answer": [{}]}}"#, answers);
+            (response, StatusCode::OK)
+        };
+        let failure = ("".to_owned(), StatusCode::BAD_REQUEST);
+        let responses = [
+            answer(&[("192.0.2.1", 60), ("192.0.2.2", 60)]),
+            answer(&[("192.0.2.66", 60)]),
+            answer(&[("192.0.2.2", 30), ("192.0.2.1", 300)]),
+            answer(&[("192.0.2.1", 60)]),
+            answer(&[("192.0.2.66", 60)]),
+            failure.clone(),
+            failure.clone(),
+            failure.clone(),
+            failure,
+        ];
+        let client = MockDnsClient::new(&responses);
+        let uris = Arc::clone(&client.uris);
+        let mut d = Dns::new(
+            client,
+            vec![
+                DnsHttpsServer::Google(Duration::from_secs(5)),
+                DnsHttpsServer::Cloudflare1_1_1_1(Duration::from_secs(5)),
+                DnsHttpsServer::Cloudflare1_0_0_1(Duration::from_secs(5)),
+            ],
+        );
+        d.set_consensus(Some(3));
+
+        // The poisoned answer of the second server is outvoted and the response of the
+        // first server that agrees is returned.
+        let r = d.resolve_a("example.com").await.unwrap();
+        assert_eq!(r.len(), 2);
+        assert_eq!((r[0].data.as_str(), r[0].TTL), ("192.0.2.1", 60));
+        assert_eq!(uris.lock().unwrap().len(), 3);
+
+        match d.resolve_a("example.com").await {
+            Err(DnsError::Disagreement(answers)) => {
+                assert_eq!(answers.len(), 3);
+                assert_eq!(
+                    answers[0].server,
+                    DnsHttpsServer::Google(Duration::from_secs(5)).uri()
+                );
+                let (code, records) = answers[1].answer.as_ref().unwrap();
+                assert_eq!(
+                    (*code, records[0].data.as_str()),
+                    (RCode::NoError, "192.0.2.66")
+                );
+                assert!(matches!(answers[2].answer, Err(QueryError::BadRequest400)));
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        // If every server fails, the attempts are returned.
+        d.set_consensus(Some(10));
+        match d.resolve_a("example.com").await {
+            Err(DnsError::Request(attempts)) => assert_eq!(attempts.len(), 3),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_consensus_formats() {
+        // Google does not quote TXT strings and Cloudflare may add unrelated records.
+        let google = r#"{"Status": 0, "Answer": [
+          {"name": "example.com.", "type": 16, "TTL": 60, "data": "v=spf1 -all"},
+          {"name": "example.com.", "type": 257, "TTL": 60, "data": "0 issue \"ca.example\""}
+        ]}"#;
+        let cloudflare = r#"{"Status": 0, "Answer": [
+          {"name": "example.com.", "type": 16, "TTL": 300, "data": "\"v=spf1 \" \"-all\""},
+          {"name": "Example.com", "type": 257, "TTL": 300,
+           "data": "\\# 17 00 05 69 73 73 75 65 63 61 2e 65 78 61 6d 70 6c 65"},
+          {"name": "other.example.", "type": 1, "TTL": 60, "data": "192.0.2.1"}
+        ]}"#;
+        let responses = [
+            (google.to_owned(), StatusCode::OK),
+            (cloudflare.to_owned(), StatusCode::OK),
+        ];
+        let mut d = Dns::new(
+            MockDnsClient::new(&responses),
+            vec![
+                DnsHttpsServer::Google(Duration::from_secs(5)),
+                DnsHttpsServer::Cloudflare1_1_1_1(Duration::from_secs(5)),
+            ],
+        );
+        d.set_consensus(Some(2));
+        let r = d.resolve_txt("example.com").await.unwrap();
+        assert_eq!(r.len(), 1);
+        assert_eq!(r[0].data, "v=spf1 -all");
+    }
+
+    #[tokio::test]
+    async fn test_answer_validation() {
+        let other = r#"{"Status": 0, "Question": [{"name": "evil.example.", "type": 1}],
+          "Answer": [{"name": "evil.example.", "type": 1, "TTL": 60, "data": "192.0.2.66"}]}"#;
+        let response = r#"{"Status": 0, "Question": [{"name": "WWW.example.com", "type": 1}],
+          "Answer": [
+            {"name": "cdn.example.net.", "type": 1, "TTL": 60, "data": "192.0.2.1"},
+            {"name": "example.com.", "type": 39, "TTL": 60, "data": "example.org."},
+            {"name": "www.example.org.", "type": 5, "TTL": 60, "data": "cdn.example.net."},
+            {"name": "www.example.com.", "type": 5, "TTL": 60, "data": "www.example.org."},
+            {"name": "bank.example.", "type": 1, "TTL": 60, "data": "192.0.2.66"},
+            {"name": "example.org.", "type": 5, "TTL": 60, "data": "evil.example."}
+          ]}"#;
+        let d = Dns::new(
+            MockDnsClient::new(&[
+                (other.to_owned(), StatusCode::OK),
+                (response.to_owned(), StatusCode::OK),
+            ]),
+            vec![
+                DnsHttpsServer::Google(Duration::from_secs(5)),
+                DnsHttpsServer::Cloudflare1_1_1_1(Duration::from_secs(5)),
+            ],
+        );
+        // The response to another question is retried on the next server and the records
+        // that are not of the CNAME chain are dropped.
+        let r = d.query("www.example.com", 1).await.unwrap();
+        let names = r
+            .Answer
+            .unwrap()
+            .into_iter()
+            .map(|a| a.name)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "cdn.example.net.",
+                "example.com.",
+                "www.example.org.",
+                "www.example.com."
+            ]
+        );
+
+        let d = Dns::new(
+            MockDnsClient::new(&[(other.to_owned(), StatusCode::OK)]),
+            vec![DnsHttpsServer::Google(Duration::from_secs(5))],
+        );
+        match d.resolve_aaaa("evil.example").await {
+            Err(DnsError::Request(attempts)) => match attempts[0].error {
+                QueryError::QuestionMismatch(ref name, rtype) => {
+                    assert_eq!((name.as_str(), rtype), ("evil.example.", 1))
+                }
+                ref e => panic!("unexpected error: {:?}", e),
+            },
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve_many() {
+        let responses = (0..5)
+            .map(|i| {
+                let response = format!(
+                    r#"{{"Status": 0, "Answer": [
+                      {{"name": "n{}.example.com.", "type": 1, "TTL": 60, "data": "192.0.2.{}"}}
+                    ]}}"#,
+                    i, i
+                );
+                (response, StatusCode::OK)
+            })
+            .collect::<Vec<_>>();
+        let d = Dns::new(
+            MockDnsClient::with_delay(&responses, Duration::from_millis(100)),
+            vec![DnsHttpsServer::Google(Duration::from_secs(5))],
+        );
+        let queries = (0..5)
+            .map(|i| (format!("n{}.example.com", i), "A"))
+            .chain(std::iter::once(("example.com".to_owned(), "BOGUS")));
+        let start = Instant::now();
+        let mut results = d.resolve_many(queries, 2).collect::<Vec<_>>().await;
+        // The five requests are made two at a time.
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert_eq!(results.len(), 6);
+        results.sort_by(|a, b| (a.0).0.cmp(&(b.0).0));
+        assert!(matches!(results[0].1, Err(DnsError::InvalidRecordType)));
+        for (i, ((name, rtype), res)) in results[1..].iter().enumerate() {
+            assert_eq!(
+                (name.as_str(), *rtype),
+                (format!("n{}.example.com", i).as_str(), "A")
+            );
+            assert_eq!(res.as_ref().unwrap()[0].data, format!("192.0.2.{}", i));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let response = r#"{"Status": 0, "Answer": [
+          {"name": "example.com.", "type": 1, "TTL": 60, "data": "192.0.2.1"}
+        ]}"#;
+        let google = DnsHttpsServer::Google(Duration::from_secs(5));
+        let client = MockDnsClient::new(&vec![(response.to_owned(), StatusCode::OK); 6]);
+        let uris = Arc::clone(&client.uris);
+        let mut d = Dns::new(
+            client,
+            vec![
+                google.clone(),
+                DnsHttpsServer::Cloudflare1_1_1_1(Duration::from_secs(5)),
+            ],
+        );
+
+        // Once the bucket of the first server is empty, queries go to the next one.
+        d.set_rate_limit(&google, 1, 2, RateLimited::Next);
+        for _ in 0..3 {
+            d.resolve_a("example.com").await.unwrap();
+        }
+        let hosts = uris
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|uri| uri.host().unwrap().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(hosts, ["dns.google", "dns.google", "1.1.1.1"]);
+
+        // Queued queries are spread at the rate given.
+        d.set_rate_limit(&google, 10, 1, RateLimited::Wait);
+        let start = Instant::now();
+        for _ in 0..3 {
+            d.resolve_a("example.com").await.unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(uris.lock().unwrap()[3..]
+            .iter()
+            .all(|uri| uri.host() == Some("dns.google")));
+    }
+
+    #[tokio::test]
+    async fn test_deadline() {
+        let responses = [
+            ("".to_owned(), StatusCode::INTERNAL_SERVER_ERROR),
+            ("".to_owned(), StatusCode::INTERNAL_SERVER_ERROR),
+        ];
+        let servers = vec![
+            DnsHttpsServer::Google(Duration::from_secs(5)),
+            DnsHttpsServer::Cloudflare1_1_1_1(Duration::from_secs(5)),
+        ];
+
+        // The second attempt is cut short by the deadline set on the instance.
+        let mut d = Dns::new(
+            MockDnsClient::with_delay(&responses, Duration::from_millis(300)),
+            servers.clone(),
+        );
+        d.set_deadline(Some(Duration::from_millis(500)));
+        let start = Instant::now();
+        match d.resolve_a("www.google.com").await {
+            Err(DnsError::DeadlineExceeded(deadline, attempts)) => {
+                assert_eq!(deadline, Duration::from_millis(500));
+                assert_eq!(attempts.len(), 2);
+                assert_eq!(attempts[0].status, Some(500));
+                assert_eq!(attempts[1].status, None);
+                match attempts[1].error {
+                    QueryError::DeadlineExceeded(deadline) => {
+                        assert_eq!(deadline, Duration::from_millis(500))
+                    }
+                    ref e => panic!("unexpected error: {:?}", e),
+                }
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // When the deadline has run out, the next server is not tried.
+        let mut d = Dns::new(MockDnsClient::new(&[]), servers.clone());
+        d.set_deadline(Some(Duration::from_secs(0)));
+        let e = d.resolve_a("www.google.com").await.unwrap_err();
+        assert!(e.is_timeout());
+        match e {
+            DnsError::DeadlineExceeded(deadline, attempts) => {
+                assert_eq!(deadline, Duration::from_secs(0));
+                assert!(attempts.is_empty());
+            }
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        // A deadline given with the call overrides the one set on the instance.
+        let d = Dns::new(
+            MockDnsClient::with_delay(&responses, Duration::from_millis(300)),
+            servers.clone(),
+        );
+        match d
+            .resolve_str_type_with_deadline("www.google.com", "a", Duration::from_millis(100))
+            .await
+        {
+            Err(DnsError::DeadlineExceeded(_, attempts)) => {
+                assert_eq!(attempts.len(), 1);
+                match attempts[0].error {
+                    QueryError::DeadlineExceeded(deadline) => {
+                        assert_eq!(deadline, Duration::from_millis(100))
+                    }
+                    ref e => panic!("unexpected error: {:?}", e),
+                }
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        // The same error is returned when all the servers are queried at once.
+        let mut d = Dns::new(
+            MockDnsClient::with_delay(&responses, Duration::from_millis(300)),
+            servers,
+        );
+        d.set_consensus(Some(2));
+        d.set_deadline(Some(Duration::from_millis(100)));
+        match d.resolve_a("www.google.com").await {
+            Err(DnsError::DeadlineExceeded(_, attempts)) => assert_eq!(attempts.len(), 2),
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        // The typed resolvers take a deadline too.
+        let d = Dns::new(
+            MockDnsClient::with_delay(&responses, Duration::from_millis(300)),
+            vec![DnsHttpsServer::Google(Duration::from_secs(5))],
+        );
+        let deadline = Duration::from_millis(100);
+        let start = Instant::now();
+        let e = d
+            .resolve_a_with_deadline("www.google.com", deadline)
+            .await
+            .unwrap_err();
+        assert!(e.is_timeout());
+        let e = d
+            .resolve_mx_and_sort_with_deadline("google.com", deadline)
+            .await
+            .unwrap_err();
+        assert!(e.is_timeout());
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn test_error_classification() {
+        let servers = vec![DnsHttpsServer::Google(Duration::from_secs(5))];
+
+        let d = Dns::new(
+            MockDnsClient::new(&[("".to_owned(), StatusCode::BAD_REQUEST)]),
+            servers.clone(),
+        );
+        let e = d.resolve_a("www.google.com").await.unwrap_err();
+        assert!(!e.is_retryable());
+        assert!(!e.is_timeout());
+        assert_eq!(e.http_status(), Some(400));
+
+        let d = Dns::new(
+            MockDnsClient::new(&[("".to_owned(), StatusCode::BAD_GATEWAY)]),
+            servers.clone(),
+        );
+        let e = d.resolve_a("www.google.com").await.unwrap_err();
+        assert!(e.is_retryable());
+        assert_eq!(e.http_status(), Some(502));
+
+        // The serde error is kept as the source of the query error.
+        let d = Dns::new(
+            MockDnsClient::new(&[("<html></html>".to_owned(), StatusCode::OK)]),
+            servers.clone(),
+        );
+        let e = d.resolve_a("www.google.com").await.unwrap_err();
+        assert_eq!(e.http_status(), Some(200));
+        let source = e.source().and_then(|e| e.source()).unwrap();
+        assert!(source.downcast_ref::<serde_json::Error>().is_some());
+
+        let d = Dns::new(
+            MockDnsClient::new(&[(r#"{"Status": 3}"#.to_owned(), StatusCode::OK)]),
+            servers,
+        );
+        let e = d.resolve_a("www.google.com").await.unwrap_err();
+        assert!(e.is_nxdomain());
+        assert!(!e.is_retryable());
+        assert_eq!(e.http_status(), None);
+    }
+
+    #[tokio::test]
+    async fn test_extended_errors() {
+        let response = String::from(
+            r#"
+{
+  "Status": 2,
+  "TC": false,
+  "RD": true,
+  "RA": true,
+  "AD": false,
+  "CD": false,
+  "Question": [
+    {
+      "name": "dnssec-failed.org",
+      "type": 1
+    }
+  ],
+  "Comment": [
+    "EDE(9): DNSKEY Missing: no SEP matching the DS found for dnssec-failed.org."
+  ]
+}"#,
+        );
+        let d = Dns::new(
+            MockDnsClient::new(&[(response, StatusCode::OK)]),
+            vec![DnsHttpsServer::Cloudflare1_1_1_1(Duration::from_secs(5))],
+        );
+        match d.resolve_a("dnssec-failed.org").await {
+            Err(DnsError::Status(RCode::ServFail, extended)) => {
+                assert_eq!(
+                    extended,
+                    vec![ExtendedError {
+                        code: ExtendedErrorCode::DnskeyMissing,
+                        extra_text: Some(
+                            "no SEP matching the DS found for dnssec-failed.org.".to_owned()
+                        ),
+                    }]
+                );
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
 }
@@ -1,37 +1,203 @@
 //! Errors returned by DoH servers.
-use crate::status::RCode;
-use std::{error::Error, fmt};
+use crate::status::{ExtendedError, RCode};
+use crate::DnsAnswer;
+use hyper::http::uri::InvalidUri;
+use std::{error::Error, fmt, net::IpAddr, time::Duration};
+use tokio::time::Elapsed;
 
 /// Errors returned before or after making a DNS request over HTTPS.
 #[derive(Debug)]
 pub enum DnsError {
-    /// An error occurred before making the request. This can when a name is of
-    /// the wrong format, connecting to servers, or parsing the server response.
+    /// An error occurred before making the request. This can happen when a name is of
+    /// the wrong format or the query URL cannot be built.
+    ///
+    /// Before 0.3, the errors of the requests, such as connection, HTTP and parsing errors,
+    /// were also returned here. They are now returned in [DnsError::Request].
     Query(QueryError),
+    /// All requests made to the servers failed or a request failed with an error that is
+    /// not retried. It contains every attempt in the order they were made. The last one
+    /// holds the error that ended the query, such as a [QueryError::Connection] or a
+    /// [QueryError::BadRequest400].
+    Request(Vec<Attempt>),
+    /// The deadline of the query ran out, either before a server could be tried or while a
+    /// request was in progress. It contains the deadline and the attempts made until then,
+    /// which all failed. A request cut short fails with [QueryError::DeadlineExceeded].
+    DeadlineExceeded(Duration, Vec<Attempt>),
     /// An error returned by the DNS server with regards to the name being queried. It
     /// occurs after a successful request/response. An example is a name that does not
-    /// exist.
-    Status(RCode),
+    /// exist. It contains the Extended DNS Errors (RFC 8914) given by the server, if any.
+    Status(RCode, Vec<ExtendedError>),
     /// An error returned when an attempt to query a record type that does not exist.
     InvalidRecordType,
     /// An error when trying to setup an empty list of servers to query.
     NoServers,
+    /// An error when a line of a hosts or zone file given to [Dns::load_zone] cannot be
+    /// parsed. It contains the line number, starting at 1, and the reason.
+    ///
+    /// [Dns::load_zone]: crate::Dns::load_zone
+    InvalidHosts(usize, String),
+    /// An error when the protection set with [Dns::set_rebinding_protection] finds an
+    /// answer with a private address, such as `127.0.0.1` or `169.254.169.254`, for a name
+    /// that is not allowed to have one. It contains the name and the address.
+    ///
+    /// [Dns::set_rebinding_protection]: crate::Dns::set_rebinding_protection
+    PrivateAddress(String, IpAddr),
+    /// An error when no response is given by most of the servers queried in the mode set
+    /// with [Dns::set_consensus]. It contains the answer of each server in the order they
+    /// are configured.
+    ///
+    /// [Dns::set_consensus]: crate::Dns::set_consensus
+    Disagreement(Vec<ServerAnswer>),
+    /// An error when the HTTPS client cannot be initialized, such as a TLS backend that
+    /// fails to load.
+    Client(Box<dyn Error + Send + Sync>),
 }
 
 impl fmt::Display for DnsError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match *self {
             DnsError::Query(ref e) => write!(f, "query error: {}", e),
-            DnsError::Status(ref e) => write!(f, "DNS response error: {}", e),
+            DnsError::Request(ref attempts) => match attempts.last() {
+                Some(attempt) => write!(
+                    f,
+                    "request error after {} attempt(s): {}",
+                    attempts.len(),
+                    attempt.error
+                ),
+                None => write!(f, "request error: no attempts made"),
+            },
+            DnsError::DeadlineExceeded(ref deadline, ref attempts) => write!(
+                f,
+                "query deadline of {:?} exceeded after {} attempt(s)",
+                deadline,
+                attempts.len()
+            ),
+            DnsError::Status(ref e, ref extended) => {
+                write!(f, "DNS response error: {}", e)?;
+                for ede in extended {
+                    write!(f, " ({})", ede)?;
+                }
+                Ok(())
+            }
             DnsError::InvalidRecordType => write!(f, "Invalid record type"),
             DnsError::NoServers => write!(f, "no servers given to resolve query"),
+            DnsError::InvalidHosts(line, ref reason) => {
+                write!(f, "invalid zone data on line {}: {}", line, reason)
+            }
+            DnsError::PrivateAddress(ref name, ref ip) => {
+                write!(f, "private address {} answered for {}", ip, name)
+            }
+            DnsError::Disagreement(ref answers) => {
+                write!(f, "servers disagree:")?;
+                for answer in answers {
+                    write!(f, " [{}]", answer)?;
+                }
+                Ok(())
+            }
+            DnsError::Client(ref e) => write!(f, "client initialization error: {}", e),
+        }
+    }
+}
+
+impl DnsError {
+    /// Returns true if the query failed because a server or the query deadline timed out.
+    pub fn is_timeout(&self) -> bool {
+        match *self {
+            DnsError::Query(ref e) => e.is_timeout(),
+            DnsError::Request(ref attempts) => {
+                attempts.last().is_some_and(|a| a.error.is_timeout())
+            }
+            DnsError::DeadlineExceeded(..) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the same query might succeed when tried again later, such as after a
+    /// connection error or a server failure.
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            DnsError::Query(ref e) => e.is_retryable(),
+            DnsError::Request(ref attempts) => {
+                attempts.last().is_some_and(|a| a.error.is_retryable())
+            }
+            DnsError::Status(RCode::ServFail, _) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the server answered that the name queried does not exist.
+    pub fn is_nxdomain(&self) -> bool {
+        matches!(*self, DnsError::Status(RCode::NXDomain, _))
+    }
+
+    /// Returns the HTTP status code received on the last request made, if any.
+    pub fn http_status(&self) -> Option<u16> {
+        match *self {
+            DnsError::Query(ref e) => e.http_status(),
+            DnsError::Request(ref attempts) => attempts.last().and_then(|a| a.status),
+            _ => None,
         }
     }
 }
 
 impl Error for DnsError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
-        None
+        match *self {
+            DnsError::Query(ref e) => Some(e),
+            DnsError::Request(ref attempts) => attempts.last().map(|a| &a.error as &dyn Error),
+            DnsError::Client(ref e) => Some(&**e),
+            _ => None,
+        }
+    }
+}
+
+/// The answer of one of the servers queried in consensus mode.
+#[derive(Debug)]
+pub struct ServerAnswer {
+    /// The URI of the server queried.
+    pub server: String,
+    /// The response code and records given by the server, or the reason the request
+    /// failed.
+    pub answer: Result<(RCode, Vec<DnsAnswer>), QueryError>,
+}
+
+impl fmt::Display for ServerAnswer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.answer {
+            Ok((code, ref records)) => {
+                write!(f, "{}: {}", self.server, code)?;
+                for record in records {
+                    write!(f, ", {} {} {}", record.name, record.r#type, record.data)?;
+                }
+                Ok(())
+            }
+            Err(ref e) => write!(f, "{}: {}", self.server, e),
+        }
+    }
+}
+
+/// A request made to one of the DoH servers that failed.
+#[derive(Debug)]
+pub struct Attempt {
+    /// The URI of the server queried.
+    pub server: String,
+    /// The full URL of the request.
+    pub url: String,
+    /// The time spent on the attempt.
+    pub elapsed: Duration,
+    /// The HTTP status code returned by the server if a response was received.
+    pub status: Option<u16>,
+    /// The reason the attempt failed.
+    pub error: QueryError,
+}
+
+impl fmt::Display for Attempt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} failed after {:?}: {}",
+            self.url, self.elapsed, self.error
+        )
     }
 }
 
@@ -43,13 +209,47 @@ pub enum QueryError {
     /// This error occurs if the name to be resolved cannot be encoded.
     InvalidName(String),
     /// This error occurs if there is a problem building the query URL.
-    InvalidEndpoint(String),
+    InvalidEndpoint(InvalidUri),
     /// This error occurs if there is a problem connecting to the server.
-    Connection(String),
+    Connection(hyper::Error),
+    /// This error occurs if the server presents none of the keys pinned for it with
+    /// [ServerOptions::pin_sha256]. It is not retried.
+    ///
+    /// [ServerOptions::pin_sha256]: crate::client::ServerOptions::pin_sha256
+    PinMismatch(hyper::Error),
+    /// This error occurs if the server does not respond within its timeout. It contains the
+    /// timeout that was given to the server.
+    Timeout(Duration, Elapsed),
     /// This error occurs if there is a problem reading a response from the server.
-    ReadResponse(String),
+    ReadResponse(hyper::Error),
     /// This error occurs if there is a problem parsing the JSON response from the server.
-    ParseResponse(String),
+    ParseResponse(serde_json::Error),
+    /// This error occurs if the response body is larger than the maximum size allowed. It
+    /// contains that maximum size.
+    ResponseTooLarge(usize),
+    /// This error occurs if the response is not of a JSON media type such as
+    /// `application/dns-json`, like an HTML page returned by a captive portal. It contains
+    /// the `Content-Type` of the response, if any.
+    InvalidContentType(Option<String>),
+    /// This error occurs if the server keeps redirecting the query. It contains the maximum
+    /// number of redirects followed.
+    TooManyRedirects(usize),
+    /// This error occurs if a redirect has no `Location` or points to another scheme, such
+    /// as from HTTPS to plain HTTP, or to another host when the server has its own client
+    /// options. It contains the location, if any.
+    InvalidRedirect(Option<String>),
+    /// This error occurs if the question of the response is not the one asked, such as a
+    /// response to another name. It contains the name and type of the question received.
+    QuestionMismatch(String, u32),
+    /// This error occurs if the rate limit set on the server with [Dns::set_rate_limit] is
+    /// reached and queries are sent to the next server.
+    ///
+    /// [Dns::set_rate_limit]: crate::Dns::set_rate_limit
+    RateLimited,
+    /// This error occurs if the total time allowed for the query, including retries, runs
+    /// out during the attempt. It contains the deadline that was given. The query then
+    /// fails with [DnsError::DeadlineExceeded].
+    DeadlineExceeded(Duration),
     /// Unknown error. This occurs if the server returns an unexpected result.
     Unknown,
     /// *HTTP Error: 400 Bad Request.*
@@ -88,8 +288,29 @@ impl fmt::Display for QueryError {
             QueryError::InvalidName(ref e) => write!(f, "invalid server name given: {}", e),
             QueryError::InvalidEndpoint(ref e) => write!(f, "invalid endpoint: {}", e),
             QueryError::Connection(ref e) => write!(f, "connection error: {}", e),
+            QueryError::PinMismatch(ref e) => {
+                write!(f, "server key does not match the pinned keys: {}", e)
+            }
+            QueryError::Timeout(ref d, _) => write!(f, "connection timeout after {:?}", d),
             QueryError::ReadResponse(ref e) => write!(f, "error reading response: {}", e),
             QueryError::ParseResponse(ref e) => write!(f, "error parsing response: {}", e),
+            QueryError::ResponseTooLarge(ref max) => {
+                write!(f, "response larger than the maximum of {} bytes", max)
+            }
+            QueryError::InvalidContentType(Some(ref t)) => {
+                write!(f, "unexpected response content type: {}", t)
+            }
+            QueryError::InvalidContentType(None) => write!(f, "response without content type"),
+            QueryError::TooManyRedirects(ref max) => {
+                write!(f, "more than {} redirects followed", max)
+            }
+            QueryError::InvalidRedirect(Some(ref l)) => write!(f, "invalid redirect to {}", l),
+            QueryError::InvalidRedirect(None) => write!(f, "redirect without location"),
+            QueryError::QuestionMismatch(ref name, ref rtype) => {
+                write!(f, "response to another question: {} type {}", name, rtype)
+            }
+            QueryError::RateLimited => write!(f, "rate limit of the server reached"),
+            QueryError::DeadlineExceeded(ref d) => write!(f, "query deadline of {:?} exceeded", d),
             QueryError::Unknown => write!(f, "unknown query error"),
             QueryError::BadRequest400 => write!(
                 f,
@@ -131,8 +352,70 @@ impl fmt::Display for QueryError {
     }
 }
 
+impl QueryError {
+    /// Returns true if the error was caused by a timeout, either on the client or on the
+    /// resolver behind the DoH server.
+    pub fn is_timeout(&self) -> bool {
+        matches!(
+            *self,
+            QueryError::Timeout(..)
+                | QueryError::DeadlineExceeded(_)
+                | QueryError::ResolverTimeout504
+        )
+    }
+
+    /// Returns true if the request can be retried, possibly on another server. Errors caused
+    /// by the request itself, such as a bad request, are not retryable since every server
+    /// would return the same.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            *self,
+            QueryError::Connection(_)
+                | QueryError::Timeout(..)
+                | QueryError::ReadResponse(_)
+                | QueryError::ParseResponse(_)
+                | QueryError::ResponseTooLarge(_)
+                | QueryError::InvalidContentType(_)
+                | QueryError::TooManyRedirects(_)
+                | QueryError::InvalidRedirect(_)
+                | QueryError::QuestionMismatch(..)
+                | QueryError::RateLimited
+                | QueryError::Unknown
+                | QueryError::TooManyRequests429
+                | QueryError::InternalServerError500
+                | QueryError::BadGateway502
+                | QueryError::ResolverTimeout504
+        )
+    }
+
+    /// Returns the HTTP status code associated with the error if it was caused by an HTTP
+    /// error response.
+    pub fn http_status(&self) -> Option<u16> {
+        match *self {
+            QueryError::BadRequest400 => Some(400),
+            QueryError::PayloadTooLarge413 => Some(413),
+            QueryError::UriTooLong414 => Some(414),
+            QueryError::UnsupportedMediaType415 => Some(415),
+            QueryError::TooManyRequests429 => Some(429),
+            QueryError::InternalServerError500 => Some(500),
+            QueryError::NotImplemented501 => Some(501),
+            QueryError::BadGateway502 => Some(502),
+            QueryError::ResolverTimeout504 => Some(504),
+            _ => None,
+        }
+    }
+}
+
 impl Error for QueryError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
-        None
+        match *self {
+            QueryError::InvalidEndpoint(ref e) => Some(e),
+            QueryError::Connection(ref e) => Some(e),
+            QueryError::PinMismatch(ref e) => Some(e),
+            QueryError::Timeout(_, ref e) => Some(e),
+            QueryError::ReadResponse(ref e) => Some(e),
+            QueryError::ParseResponse(ref e) => Some(e),
+            _ => None,
+        }
     }
 }
@@ -0,0 +1,325 @@
+// Local records that override the servers, loaded from hosts files or simple zone files.
+use crate::error::DnsError;
+use crate::wire;
+use crate::DnsAnswer;
+use std::{collections::HashMap, net::IpAddr};
+
+// The TTL of the records of hosts files unless another one is set.
+pub(crate) const DEFAULT_HOSTS_TTL: u32 = 3600;
+// Maximum number of local CNAME records followed while answering a query.
+const MAX_CNAMES: usize = 8;
+
+const RTYPE_A: u32 = 1;
+const RTYPE_CNAME: u32 = 5;
+const RTYPE_PTR: u32 = 12;
+const RTYPE_TXT: u32 = 16;
+const RTYPE_AAAA: u32 = 28;
+
+// A record of a local name. Records without a TTL, those of hosts files, are given the
+// TTL set on the hosts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct HostRecord {
+    rtype: u32,
+    ttl: Option<u32>,
+    data: String,
+}
+
+// The answers to a query for a local name. When its CNAME chain leads to a name without
+// local records, the target is left to be resolved through the servers.
+#[derive(Debug)]
+pub(crate) struct LocalAnswers {
+    pub(crate) answers: Vec<DnsAnswer>,
+    pub(crate) target: Option<String>,
+}
+
+// The local records by lowercase name without the trailing dot.
+#[derive(Debug, Clone)]
+pub(crate) struct Hosts {
+    entries: HashMap<String, Vec<HostRecord>>,
+    pub(crate) ttl: u32,
+}
+
+impl Hosts {
+    pub(crate) fn new() -> Hosts {
+        Hosts {
+            entries: HashMap::new(),
+            ttl: DEFAULT_HOSTS_TTL,
+        }
+    }
+
+    // Adds the entries of a hosts file: an address followed by its names, each getting an
+    // A or AAAA record and the address a PTR record to the first name. Lines that are not
+    // entries, such as those with a scoped IPv6 address, are skipped like the system does.
+    pub(crate) fn load_hosts(&mut self, contents: &str) {
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let ip = match fields.next().map(str::parse::<IpAddr>) {
+                Some(Ok(ip)) => ip,
+                _ => continue,
+            };
+            let names = fields.collect::<Vec<_>>();
+            let rtype = if ip.is_ipv4() { RTYPE_A } else { RTYPE_AAAA };
+            for name in &names {
+                self.insert(name, rtype, None, ip.to_string());
+            }
+            if let Some(name) = names.first() {
+                self.insert(&reverse_name(ip), RTYPE_PTR, None, absolute(name));
+            }
+        }
+    }
+
+    // Adds the records of a simple zone file where each line is
+    // `<name> [<ttl>] [IN] <type> <data>`. Names are absolute with or without the trailing
+    // dot and only A, AAAA, PTR, CNAME and TXT records are supported. A `$TTL` line sets the
+    // TTL of the records that follow without one. Comments start with a `;`.
+    pub(crate) fn load_zone(&mut self, contents: &str) -> Result<(), DnsError> {
+        let mut default_ttl = None;
+        let mut records = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            let invalid = |reason: &str| DnsError::InvalidHosts(i + 1, reason.to_owned());
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut rest = line;
+            let mut field = || {
+                let field = rest.split_whitespace().next().unwrap_or_default();
+                rest = rest.trim_start()[field.len()..].trim_start();
+                field
+            };
+            let name = field();
+            if name.eq_ignore_ascii_case("$TTL") {
+                let ttl = field().parse::<u32>().map_err(|_| invalid("invalid TTL"))?;
+                default_ttl = Some(ttl);
+                continue;
+            }
+            if name.starts_with('$') {
+                return Err(invalid("unsupported directive"));
+            }
+            let mut next = field();
+            let mut ttl = default_ttl;
+            if let Ok(t) = next.parse::<u32>() {
+                ttl = Some(t);
+                next = field();
+            }
+            if next.eq_ignore_ascii_case("IN") {
+                next = field();
+            }
+            let rtype = match next.to_ascii_uppercase().as_str() {
+                "A" => RTYPE_A,
+                "AAAA" => RTYPE_AAAA,
+                "PTR" => RTYPE_PTR,
+                "CNAME" => RTYPE_CNAME,
+                "TXT" => RTYPE_TXT,
+                "" => return Err(invalid("missing record type")),
+                _ => return Err(invalid("unsupported record type")),
+            };
+            let data = match rtype {
+                RTYPE_PTR | RTYPE_CNAME => absolute(rest),
+                _ => rest.to_owned(),
+            };
+            // The data has to be valid for the record to be answered over the wire too.
+            if data.is_empty() || wire::rdata_from_text(rtype as u16, &data).is_err() {
+                return Err(invalid("invalid record data"));
+            }
+            if wire::rdata_from_text(RTYPE_CNAME as u16, &absolute(name)).is_err() {
+                return Err(invalid("invalid name"));
+            }
+            records.push((name.to_owned(), rtype, ttl, data));
+        }
+        // Nothing is added if any line is invalid.
+        for (name, rtype, ttl, data) in records {
+            self.insert(&name, rtype, ttl, data);
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, name: &str, rtype: u32, ttl: Option<u32>, data: String) {
+        // Names are looked up in their puny encoded form like they are queried.
+        let name = idna::domain_to_ascii(name).unwrap_or_else(|_| name.to_owned());
+        let record = HostRecord { rtype, ttl, data };
+        let records = self.entries.entry(key(&name)).or_default();
+        if !records.contains(&record) {
+            records.push(record);
+        }
+    }
+
+    // Answers a query if the name is local. A local name without records of the type
+    // queried has no answers, except for a CNAME which is followed within the local names
+    // and gives the target to resolve when it leaves them. A type of 0 is `ANY`.
+    pub(crate) fn lookup(&self, name: &str, rtype: u32) -> Option<LocalAnswers> {
+        let mut name = absolute(name).to_ascii_lowercase();
+        let mut records = self.entries.get(&key(&name))?;
+        let mut answers = Vec::new();
+        let mut target = None;
+        for _ in 0..MAX_CNAMES {
+            let matching = records
+                .iter()
+                .filter(|r| r.rtype == rtype || rtype == 0)
+                .collect::<Vec<_>>();
+            let cname = records.iter().find(|r| r.rtype == RTYPE_CNAME);
+            let next = match cname {
+                Some(cname) if matching.is_empty() => cname,
+                _ => {
+                    answers.extend(matching.into_iter().map(|r| self.answer(&name, r)));
+                    break;
+                }
+            };
+            answers.push(self.answer(&name, next));
+            name = next.data.to_ascii_lowercase();
+            records = match self.entries.get(&key(&name)) {
+                Some(records) => records,
+                None => {
+                    target = Some(name);
+                    break;
+                }
+            };
+        }
+        Some(LocalAnswers { answers, target })
+    }
+
+    fn answer(&self, name: &str, record: &HostRecord) -> DnsAnswer {
+        DnsAnswer {
+            name: name.to_owned(),
+            r#type: record.rtype,
+            TTL: record.ttl.unwrap_or(self.ttl),
+            data: record.data.clone(),
+        }
+    }
+}
+
+fn key(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn absolute(name: &str) -> String {
+    if name.ends_with('.') {
+        name.to_owned()
+    } else {
+        format!("{}.", name)
+    }
+}
+
+// Removes a comment from a zone file line unless the `;` is quoted.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+// Returns the name of the PTR record of an address.
+fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let o = ip.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa.", o[3], o[2], o[1], o[0])
+        }
+        IpAddr::V6(ip) => {
+            let mut name = String::new();
+            for b in ip.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", b & 0x0f, b >> 4));
+            }
+            name.push_str("ip6.arpa.");
+            name
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_hosts() {
+        let mut hosts = Hosts::new();
+        hosts.load_hosts(
+            "# local names\n127.0.0.1 localhost\n10.0.0.5 db.internal db # primary\n\
+             ::1 localhost ip6-localhost\nfe80::1%lo0 scoped\nbogus line\n",
+        );
+        let a = hosts.lookup("DB.internal.", RTYPE_A).unwrap().answers;
+        assert_eq!(a[0].name, "db.internal.");
+        assert_eq!(a[0].data, "10.0.0.5");
+        assert_eq!(a[0].TTL, DEFAULT_HOSTS_TTL);
+        assert_eq!(
+            hosts.lookup("db", RTYPE_A).unwrap().answers[0].data,
+            "10.0.0.5"
+        );
+        assert_eq!(
+            hosts.lookup("localhost", RTYPE_AAAA).unwrap().answers[0].data,
+            "::1"
+        );
+        assert!(hosts.lookup("db", RTYPE_AAAA).unwrap().answers.is_empty());
+        assert!(hosts.lookup("scoped", RTYPE_A).is_none());
+        assert!(hosts.lookup("example.com", RTYPE_A).is_none());
+        let ptr = hosts
+            .lookup("5.0.0.10.in-addr.arpa", RTYPE_PTR)
+            .unwrap()
+            .answers;
+        assert_eq!(ptr[0].data, "db.internal.");
+        let ptr = hosts.lookup(&reverse_name("::1".parse().unwrap()), RTYPE_PTR);
+        assert_eq!(ptr.unwrap().answers[0].data, "localhost.");
+        assert!(reverse_name("::1".parse().unwrap()).starts_with("1.0.0.0."));
+    }
+
+    #[test]
+    fn test_load_zone() {
+        let mut hosts = Hosts::new();
+        hosts
+            .load_zone(
+                "$TTL 300\n\
+                 www.test.  IN CNAME web.test.\n\
+                 web.test.  60 IN A 192.0.2.1\n\
+                 web.test.  AAAA 2001:db8::1 ; comment\n\
+                 txt.test.  TXT \"a; b\" \"c\"\n\
+                 ext.test.  CNAME www.ext.test.\n\
+                 www.ext.test.  CNAME cdn.example.net.\n",
+            )
+            .unwrap();
+        let a = hosts.lookup("www.test", RTYPE_A).unwrap().answers;
+        assert_eq!(a.len(), 2);
+        assert_eq!((a[0].r#type, a[0].TTL), (RTYPE_CNAME, 300));
+        assert_eq!((a[1].name.as_str(), a[1].TTL), ("web.test.", 60));
+        assert_eq!(
+            hosts.lookup("www.test", RTYPE_CNAME).unwrap().answers.len(),
+            1
+        );
+        assert_eq!(hosts.lookup("web.test", 0).unwrap().answers.len(), 2);
+        assert!(hosts.lookup("www.test", RTYPE_A).unwrap().target.is_none());
+
+        // A chain leaving the local names ends on the target to resolve.
+        let ext = hosts.lookup("ext.test", RTYPE_A).unwrap();
+        assert_eq!(ext.answers.len(), 2);
+        assert_eq!(ext.target.as_deref(), Some("cdn.example.net."));
+        assert!(hosts
+            .lookup("ext.test", RTYPE_CNAME)
+            .unwrap()
+            .target
+            .is_none());
+        assert_eq!(
+            hosts.lookup("txt.test", RTYPE_TXT).unwrap().answers[0].data,
+            "\"a; b\" \"c\""
+        );
+
+        for (zone, line) in &[
+            ("a.test. A 192.0.2.1\nb.test. MX 10 mx.test.", 2),
+            ("a.test. A not-an-ip", 1),
+            ("$ORIGIN test.", 1),
+            ("a.test.", 1),
+        ] {
+            match Hosts::new().load_zone(zone) {
+                Err(DnsError::InvalidHosts(l, _)) => assert_eq!(l, *line),
+                r => panic!("unexpected result for {}: {:?}", zone, r),
+            }
+        }
+    }
+}
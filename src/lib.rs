@@ -16,7 +16,11 @@
 //!
 //! # Example
 //! ```
-//! use doh_dns::{client::HyperDnsClient, Dns, DnsHttpsServer};
+//! # #[cfg(not(feature = "native-tls"))]
+//! # use doh_dns::client::RustlsDnsClient as HyperDnsClient;
+//! # #[cfg(feature = "native-tls")]
+//! use doh_dns::client::HyperDnsClient;
+//! use doh_dns::{Dns, DnsHttpsServer};
 //! use std::time::Duration;
 //! use tokio;
 //!
@@ -53,20 +57,53 @@
 //! }
 //! ```
 //!
+//! # TLS Backends
+//! By default, the system TLS library is used through `native-tls` with
+//! [client::HyperDnsClient]. The `rustls` feature provides [client::RustlsDnsClient]
+//! which uses `rustls` with bundled root certificates instead.
+//!
+//! The TLS options of each server, such as a custom CA bundle or pinned keys, and the
+//! connection settings, such as timeouts and pooling, are set with a
+//! [client::ClientBuilder] and the client is then given to [Dns::with_client].
+//!
+//! # Resolving for hyper clients
+//! A [Dns] can resolve the names of other hyper clients with a [resolver::DnsResolver]
+//! given to `HttpConnector::new_with_resolver`.
+//!
+//! # Stub resolver
+//! A [stub::StubServer] answers the DNS queries of other software over UDP and TCP by
+//! forwarding them to the DoH servers.
+//!
+//! # DoH server
+//! A [server::DohServer] exposes the `/resolve` JSON API and the RFC 8484 `/dns-query`
+//! endpoint, answered with a [Dns] and its upstream servers.
+//!
 //! # Logging
 //! This library uses the `log` crate to log errors during retries. Please see that create
 //! on methods on display such errors. If no logger is setup, nothing will be logged.
 #![feature(proc_macro_hygiene)]
 #![feature(stmt_expr_attributes)]
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+mod bind;
+mod blocklist;
 pub mod client;
 mod dns;
 pub mod error;
+mod hosts;
+#[cfg(feature = "native-tls")]
+mod native;
+mod proxy;
+mod ratelimit;
+mod rebinding;
+pub mod resolver;
+pub mod server;
 pub mod status;
+pub mod stub;
+mod tls;
+pub mod wire;
 #[macro_use]
 extern crate serde_derive;
-extern crate num;
-#[macro_use]
-extern crate num_derive;
+use std::collections::HashMap;
 use std::time::Duration;
 
 /// The data associated for requests returned by the DNS over HTTPS servers.
@@ -87,9 +124,61 @@ pub struct DnsAnswer {
 #[allow(non_snake_case)]
 #[derive(Deserialize, Debug, Serialize)]
 struct DnsResponse {
-    Status: u32,
+    Status: u16,
+    Question: Option<Vec<DnsQuestion>>,
     Answer: Option<Vec<DnsAnswer>>,
-    Comment: Option<String>,
+    Comment: Option<DnsComment>,
+}
+
+// The question of a response, which is checked against the query made.
+#[derive(Deserialize, Debug, Serialize)]
+struct DnsQuestion {
+    name: String,
+    r#type: u32,
+}
+
+// Google returns a single comment while Cloudflare returns a list of them.
+#[derive(Deserialize, Debug, Serialize)]
+#[serde(untagged)]
+enum DnsComment {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl DnsResponse {
+    // Extended DNS Errors are given in the comments as `EDE(<code>): <text>`, such as
+    // `EDE(9): DNSKEY Missing: no SEP matching the DS found for dnssec-failed.org.`.
+    fn extended_errors(&self) -> Vec<status::ExtendedError> {
+        let comments = match self.Comment {
+            None => return vec![],
+            Some(DnsComment::One(ref c)) => std::slice::from_ref(c),
+            Some(DnsComment::Many(ref c)) => c.as_slice(),
+        };
+        comments
+            .iter()
+            .filter_map(|c| {
+                let rest = c.trim().strip_prefix("EDE(")?;
+                let end = rest.find(')')?;
+                let code: status::ExtendedErrorCode = rest[..end].parse::<u16>().ok()?.into();
+                let mut text = rest[end + 1..].trim_start_matches(':').trim();
+                // Remove the description of the code since it is already given by the code.
+                let description = code.to_string();
+                if let Some(t) = text.strip_prefix(description.as_str()) {
+                    if t.is_empty() || t.starts_with(':') {
+                        text = t.trim_start_matches(':').trim();
+                    }
+                }
+                Some(status::ExtendedError {
+                    code,
+                    extra_text: if text.is_empty() {
+                        None
+                    } else {
+                        Some(text.to_string())
+                    },
+                })
+            })
+            .collect()
+    }
 }
 
 /// The list of DNS over HTTPS servers allowed to query with their respective timeouts.
@@ -108,6 +197,58 @@ pub enum DnsHttpsServer {
     /// Cloudflare's `1.0.0.1` DOH server. Cloudflare does not respond to `ANY` Dns
     /// requests so [Dns::resolve_any] will always return an error.
     Cloudflare1_0_0_1(Duration),
+    /// A DoH server that supports the JSON API, such as an internal resolver. It is given by
+    /// the URL of the API without query, such as `https://doh.example.com/resolve`. Names
+    /// in the URL are resolved by the system. Queries to URLs that are not `https` fail.
+    Custom(String, Duration),
+}
+
+/// Where the queries for the names under a domain are sent. Routes are set with
+/// [Dns::set_route].
+#[derive(Clone)]
+pub enum Route {
+    /// The names are queried on the given servers, in order, instead of the default ones.
+    Servers(Vec<DnsHttpsServer>),
+    /// The names are not queried and fail with a [status::RCode::Refused] status, such as
+    /// for `onion` names that must not leak to public servers.
+    Refuse,
+}
+
+/// How queries for blocked names are answered. All the responses have a Blocked Extended
+/// DNS Error (RFC 8914). The response is set with [Dns::set_block_response].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockResponse {
+    /// The name does not exist. This is the default.
+    NxDomain,
+    /// `A` queries are answered with `0.0.0.0` and `AAAA` queries with `::`, so that
+    /// connections fail right away. Other types have no answers.
+    Unspecified,
+    /// The query is refused.
+    Refused,
+}
+
+/// How answers with private addresses are handled when the rebinding protection is enabled
+/// with [Dns::set_rebinding_protection]. It is set with [Dns::set_rebinding_response].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RebindingResponse {
+    /// The query fails with [error::DnsError::PrivateAddress]. This is the default.
+    Reject,
+    /// The `A` and `AAAA` records with private addresses are dropped and the other records
+    /// are returned.
+    Drop,
+}
+
+/// What happens to a query when the rate limit of a server set with [Dns::set_rate_limit]
+/// is reached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimited {
+    /// The query waits for its turn, within the timeout of the server and the deadline.
+    /// If its turn comes after them, it is sent to the next server right away as with
+    /// [RateLimited::Next].
+    Wait,
+    /// The query is sent to the next server right away. The attempt fails with
+    /// [error::QueryError::RateLimited].
+    Next,
 }
 
 impl DnsHttpsServer {
@@ -116,6 +257,7 @@ impl DnsHttpsServer {
             Self::Google(_) => "https://dns.google/resolve",
             Self::Cloudflare1_1_1_1(_) => "https://1.1.1.1/dns-query",
             Self::Cloudflare1_0_0_1(_) => "https://1.0.0.1/dns-query",
+            Self::Custom(uri, _) => uri,
         }
     }
     fn timeout(&self) -> Duration {
@@ -123,6 +265,7 @@ impl DnsHttpsServer {
             Self::Google(t) => *t,
             Self::Cloudflare1_1_1_1(t) => *t,
             Self::Cloudflare1_0_0_1(t) => *t,
+            Self::Custom(_, t) => *t,
         }
     }
 }
@@ -131,4 +274,12 @@ impl DnsHttpsServer {
 pub struct Dns<C: client::DnsClient> {
     client: C,
     servers: Vec<DnsHttpsServer>,
+    deadline: Option<Duration>,
+    max_response_size: usize,
+    routes: HashMap<String, Route>,
+    hosts: hosts::Hosts,
+    blocklist: blocklist::Blocklist,
+    rebinding: rebinding::Rebinding,
+    consensus: Option<usize>,
+    rate_limits: HashMap<String, ratelimit::TokenBucket>,
 }
@@ -0,0 +1,164 @@
+// An async TLS stream over native-tls. Unlike tokio-tls, it gives access to the
+// certificate and the protocol negotiated with the server.
+use futures_util::future::poll_fn;
+use hyper::client::connect::{Connected, Connection};
+use native_tls_crate::{Certificate, HandshakeError, MidHandshakeTlsStream, TlsConnector};
+use std::{
+    io::{self, Read, Write},
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+// Performs the TLS handshake with the server over the given stream.
+pub(crate) async fn connect<S>(
+    connector: &TlsConnector,
+    domain: &str,
+    stream: S,
+) -> io::Result<TlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = Some(AllowStd {
+        inner: stream,
+        waker: None,
+    });
+    let mut handshake: Option<MidHandshakeTlsStream<AllowStd<S>>> = None;
+    poll_fn(|cx| {
+        let result = match handshake.take() {
+            Some(mut mid) => {
+                mid.get_mut().set_waker(cx);
+                mid.handshake()
+            }
+            None => {
+                let mut stream = stream.take().expect("handshake polled after completion");
+                stream.set_waker(cx);
+                connector.connect(domain, stream)
+            }
+        };
+        match result {
+            Ok(stream) => Poll::Ready(Ok(TlsStream(stream))),
+            Err(HandshakeError::WouldBlock(mid)) => {
+                handshake = Some(mid);
+                Poll::Pending
+            }
+            Err(HandshakeError::Failure(e)) => Poll::Ready(Err(io::Error::other(e))),
+        }
+    })
+    .await
+}
+
+// Gives a blocking interface to an async stream, which fails with `WouldBlock` instead of
+// blocking, for native-tls. The waker of the task polling the TLS stream is set before
+// each call.
+#[derive(Debug)]
+struct AllowStd<S> {
+    inner: S,
+    waker: Option<Waker>,
+}
+
+impl<S> AllowStd<S> {
+    fn set_waker(&mut self, cx: &Context<'_>) {
+        match self.waker {
+            Some(ref waker) if waker.will_wake(cx.waker()) => {}
+            _ => self.waker = Some(cx.waker().clone()),
+        }
+    }
+
+    fn with_context<F, R>(&mut self, f: F) -> io::Result<R>
+    where
+        F: FnOnce(Pin<&mut S>, &mut Context<'_>) -> Poll<io::Result<R>>,
+        S: Unpin,
+    {
+        let waker = match self.waker {
+            Some(ref waker) => waker,
+            None => return Err(io::ErrorKind::WouldBlock.into()),
+        };
+        match f(Pin::new(&mut self.inner), &mut Context::from_waker(waker)) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> Read for AllowStd<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.with_context(|stream, cx| stream.poll_read(cx, buf))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Write for AllowStd<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.with_context(|stream, cx| stream.poll_write(cx, buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.with_context(|stream, cx| stream.poll_flush(cx))
+    }
+}
+
+// A TLS stream whose handshake with the server is complete.
+#[derive(Debug)]
+pub(crate) struct TlsStream<S>(native_tls_crate::TlsStream<AllowStd<S>>);
+
+impl<S: AsyncRead + AsyncWrite + Unpin> TlsStream<S> {
+    // Returns the certificate of the server.
+    pub(crate) fn peer_certificate(&self) -> io::Result<Option<Certificate>> {
+        self.0.peer_certificate().map_err(io::Error::other)
+    }
+
+    // Runs a blocking TLS operation on behalf of the task, turning `WouldBlock` into
+    // `Pending`.
+    fn poll_with<F, R>(&mut self, cx: &Context<'_>, f: F) -> Poll<io::Result<R>>
+    where
+        F: FnOnce(&mut native_tls_crate::TlsStream<AllowStd<S>>) -> io::Result<R>,
+    {
+        self.0.get_mut().set_waker(cx);
+        match f(&mut self.0) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
+            result => Poll::Ready(result),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for TlsStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_with(cx, |stream| stream.read(buf))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for TlsStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_with(cx, |stream| stream.write(buf))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_with(cx, |stream| stream.flush())
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.poll_with(cx, |stream| stream.shutdown()) {
+            Poll::Ready(Ok(())) => Pin::new(&mut self.0.get_mut().inner).poll_shutdown(cx),
+            poll => poll,
+        }
+    }
+}
+
+// Tells hyper when HTTP/2 was negotiated with ALPN.
+impl<S: AsyncRead + AsyncWrite + Connection + Unpin> Connection for TlsStream<S> {
+    fn connected(&self) -> Connected {
+        let connected = self.0.get_ref().inner.connected();
+        match self.0.negotiated_alpn() {
+            Ok(Some(ref protocol)) if protocol == b"h2" => connected.negotiated_h2(),
+            _ => connected,
+        }
+    }
+}
@@ -0,0 +1,155 @@
+// Client side rate limiting of the queries sent to a server with a token bucket.
+use crate::error::QueryError;
+use crate::RateLimited;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::time::delay_for;
+
+// A bucket that holds up to `burst` tokens and is refilled with `rate` tokens per second.
+// Each query takes a token.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    when_limited: RateLimited,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    // Negative when queued queries have taken tokens ahead of time.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    // Creates a full bucket. The rate and burst are at least 1.
+    pub(crate) fn new(per_second: u32, burst: u32, when_limited: RateLimited) -> TokenBucket {
+        let burst = f64::from(burst.max(1));
+        TokenBucket {
+            rate: f64::from(per_second.max(1)),
+            burst,
+            when_limited,
+            state: Mutex::new(BucketState {
+                tokens: burst,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    // Takes a token and returns how long to wait before it can be used. When the bucket is
+    // empty and queries are not queued, no token is taken and `None` is returned.
+    fn take(&self, now: Instant) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        let elapsed = now.saturating_duration_since(state.updated).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.rate).min(self.burst);
+        state.updated = now;
+        if state.tokens < 1.0 && self.when_limited == RateLimited::Next {
+            return None;
+        }
+        state.tokens -= 1.0;
+        Some(Duration::from_secs_f64(
+            (-state.tokens).max(0.0) / self.rate,
+        ))
+    }
+
+    // Returns a token taken by a query that was not sent.
+    fn give_back(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.tokens = (state.tokens + 1.0).min(self.burst);
+    }
+
+    // Waits until a query can be sent, or fails right away if the limit is reached and
+    // queries go to the next server, or if the wait would outlast the attempt.
+    pub(crate) async fn acquire(&self, timeout: Duration) -> Result<(), QueryError> {
+        let wait = self.take(Instant::now()).ok_or(QueryError::RateLimited)?;
+        if wait == Duration::from_secs(0) {
+            return Ok(());
+        }
+        if wait >= timeout {
+            self.give_back();
+            return Err(QueryError::RateLimited);
+        }
+        let mut reserved = Reserved {
+            bucket: self,
+            sent: false,
+        };
+        delay_for(wait).await;
+        reserved.sent = true;
+        Ok(())
+    }
+}
+
+// Gives the token of a waiting query back if the wait is cancelled, such as by a timeout.
+struct Reserved<'a> {
+    bucket: &'a TokenBucket,
+    sent: bool,
+}
+
+impl Drop for Reserved<'_> {
+    fn drop(&mut self) {
+        if !self.sent {
+            self.bucket.give_back();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take() {
+        let bucket = TokenBucket::new(10, 2, RateLimited::Next);
+        let start = Instant::now();
+        assert_eq!(bucket.take(start), Some(Duration::from_secs(0)));
+        assert_eq!(bucket.take(start), Some(Duration::from_secs(0)));
+        assert_eq!(bucket.take(start), None);
+        // A token is added every 100 ms, up to the burst.
+        let later = start + Duration::from_millis(100);
+        assert_eq!(bucket.take(later), Some(Duration::from_secs(0)));
+        assert_eq!(bucket.take(later), None);
+        let much_later = start + Duration::from_secs(10);
+        assert!(bucket.take(much_later).is_some());
+        assert!(bucket.take(much_later).is_some());
+        assert!(bucket.take(much_later).is_none());
+
+        // Queued queries wait for their turn.
+        let bucket = TokenBucket::new(10, 1, RateLimited::Wait);
+        assert_eq!(bucket.take(start), Some(Duration::from_secs(0)));
+        let wait = bucket.take(start).unwrap();
+        assert!((wait.as_secs_f64() - 0.1).abs() < 1e-9);
+        let wait = bucket.take(start).unwrap();
+        assert!((wait.as_secs_f64() - 0.2).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn test_acquire_timeout() {
+        let bucket = TokenBucket::new(1, 1, RateLimited::Wait);
+        let timeout = Duration::from_secs(5);
+        bucket.acquire(timeout).await.unwrap();
+
+        // Waits cut short by the timeout of the attempt give their token back.
+        for _ in 0..20 {
+            let acquire = bucket.acquire(timeout);
+            assert!(tokio::time::timeout(Duration::from_millis(5), acquire)
+                .await
+                .is_err());
+        }
+        let wait = bucket.take(Instant::now()).unwrap();
+        assert!(wait <= Duration::from_secs(1), "{:?}", wait);
+        bucket.give_back();
+
+        // A wait longer than the attempt fails right away without taking a token.
+        let start = Instant::now();
+        match bucket.acquire(Duration::from_millis(500)).await {
+            Err(QueryError::RateLimited) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert!(start.elapsed() < Duration::from_millis(100));
+        let wait = bucket.take(Instant::now()).unwrap();
+        assert!(wait <= Duration::from_secs(1), "{:?}", wait);
+    }
+}
@@ -0,0 +1,259 @@
+// Protection against DNS rebinding: public names answered with addresses of private
+// networks, such as loopback or cloud metadata addresses.
+use crate::error::DnsError;
+use crate::{DnsAnswer, RebindingResponse};
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+const RTYPE_A: u32 = 1;
+const RTYPE_AAAA: u32 = 28;
+
+// The domains whose names may be answered with private addresses, lowercase and puny
+// encoded without the trailing dot. Each entry also applies to the names under it.
+#[derive(Debug, Clone)]
+pub(crate) struct Rebinding {
+    pub(crate) enabled: bool,
+    pub(crate) response: RebindingResponse,
+    allowed: HashSet<String>,
+}
+
+impl Rebinding {
+    pub(crate) fn new() -> Rebinding {
+        Rebinding {
+            enabled: false,
+            response: RebindingResponse::Reject,
+            allowed: HashSet::new(),
+        }
+    }
+
+    pub(crate) fn allow(&mut self, domain: String) {
+        self.allowed.insert(domain);
+    }
+
+    pub(crate) fn disallow(&mut self, domain: &str) {
+        self.allowed.remove(domain);
+    }
+
+    // Returns an error with the first private address found in the A or AAAA answers of a
+    // name, or drops those answers, unless the protection is disabled or the name is
+    // allowed.
+    pub(crate) fn filter(
+        &self,
+        name: &str,
+        answers: Vec<DnsAnswer>,
+    ) -> Result<Vec<DnsAnswer>, DnsError> {
+        if !self.enabled {
+            return Ok(answers);
+        }
+        let ip = match answers.iter().find_map(private_address) {
+            Some(ip) => ip,
+            None => return Ok(answers),
+        };
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if self.is_allowed(&name) {
+            return Ok(answers);
+        }
+        match self.response {
+            RebindingResponse::Reject => Err(DnsError::PrivateAddress(name, ip)),
+            RebindingResponse::Drop => Ok(answers
+                .into_iter()
+                .filter(|a| private_address(a).is_none())
+                .collect()),
+        }
+    }
+
+    fn is_allowed(&self, name: &str) -> bool {
+        let mut suffix = name;
+        loop {
+            if self.allowed.contains(suffix) {
+                return true;
+            }
+            match suffix.find('.') {
+                Some(i) => suffix = &suffix[i + 1..],
+                None => return false,
+            }
+        }
+    }
+}
+
+// Returns the address of an A or AAAA answer if it is private.
+fn private_address(answer: &DnsAnswer) -> Option<IpAddr> {
+    if answer.r#type != RTYPE_A && answer.r#type != RTYPE_AAAA {
+        return None;
+    }
+    answer.data.parse().ok().filter(is_private)
+}
+
+// Returns true if the address is unspecified, loopback, private, link-local, shared
+// (CGNAT), multicast, reserved or of another special-purpose range that is not reachable
+// on the internet. Cloud metadata addresses such as 169.254.169.254, 100.100.100.200,
+// 192.0.0.192 and fd00:ec2::254 fall in these ranges. IPv4 addresses embedded in IPv6
+// ones by NAT64, 6to4, Teredo and the mapped or compatible formats are checked as well.
+pub(crate) fn is_private(ip: &IpAddr) -> bool {
+    match *ip {
+        IpAddr::V4(ip) => is_private_v4(ip),
+        IpAddr::V6(ip) => is_private_v6(ip),
+    }
+}
+
+fn is_private_v4(ip: Ipv4Addr) -> bool {
+    let o = ip.octets();
+    o[0] == 0
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        // 100.64.0.0/10
+        || (o[0] == 100 && o[1] & 0xc0 == 64)
+        // 192.0.0.0/24, IETF protocol assignments
+        || (o[0] == 192 && o[1] == 0 && o[2] == 0)
+        // 198.18.0.0/15, benchmarking
+        || (o[0] == 198 && o[1] & 0xfe == 18)
+        // 224.0.0.0/4 multicast and 240.0.0.0/4 reserved, including the broadcast address
+        || o[0] >= 224
+}
+
+fn is_private_v6(ip: Ipv6Addr) -> bool {
+    if let Some(v4) = embedded_v4(ip) {
+        return is_private_v4(v4);
+    }
+    let s = ip.segments();
+    ip.is_unspecified()
+        || ip.is_loopback()
+        // 100::/64, discard-only
+        || (s[0] == 0x100 && s[1..4] == [0, 0, 0])
+        // 64:ff9b:1::/48, local-use NAT64
+        || (s[0] == 0x64 && s[1] == 0xff9b && s[2] == 1)
+        // fc00::/7
+        || s[0] & 0xfe00 == 0xfc00
+        // fe80::/10 link-local and fec0::/10 site-local
+        || s[0] & 0xff80 == 0xfe80
+        // ff00::/8
+        || s[0] & 0xff00 == 0xff00
+}
+
+// Returns the IPv4 address embedded in an IPv6 address that translates to it or tunnels
+// to it.
+fn embedded_v4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let s = ip.segments();
+    let v4 = |high: u16, low: u16| Ipv4Addr::from((u32::from(high) << 16) | u32::from(low));
+    match s {
+        // ::ffff:0:0/96 mapped and ::/96 compatible, except :: and ::1.
+        [0, 0, 0, 0, 0, 0xffff, high, low] => Some(v4(high, low)),
+        [0, 0, 0, 0, 0, 0, high, low] if high != 0 => Some(v4(high, low)),
+        // 64:ff9b::/96, NAT64
+        [0x64, 0xff9b, 0, 0, 0, 0, high, low] => Some(v4(high, low)),
+        // 2002::/16, 6to4
+        [0x2002, high, low, ..] => Some(v4(high, low)),
+        // 2001::/32, Teredo, whose client address is inverted
+        [0x2001, 0, .., high, low] => Some(v4(!high, !low)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_private() {
+        for ip in &[
+            "0.0.0.0",
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "100.100.100.200",
+            "255.255.255.255",
+            "::",
+            "::1",
+            "fd00:ec2::254",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "192.0.0.192",
+            "198.18.0.1",
+            "224.0.0.1",
+            "240.0.0.1",
+            "::127.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b:1::8.8.8.8",
+            "2002:7f00:1::",
+            "2002:a9fe:a9fe::1",
+            "2001:0:4136:e378:8000:63bf:80ff:fffe",
+            "100::1",
+            "fec0::1",
+            "ff02::1",
+        ] {
+            assert!(is_private(&ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in &[
+            "93.184.216.34",
+            "172.32.0.1",
+            "100.128.0.1",
+            "8.8.8.8",
+            "2606:4700:4700::1111",
+            "::ffff:8.8.8.8",
+            "192.0.2.1",
+            "198.20.0.1",
+            "223.255.255.255",
+            "64:ff9b::808:808",
+            "2002:808:808::1",
+            "2001:0:4136:e378:8000:63bf:f7f7:f7f7",
+            "2001:4860:4860::8888",
+        ] {
+            assert!(!is_private(&ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let answer = |r#type, data: &str| DnsAnswer {
+            name: "www.example.com.".to_owned(),
+            r#type,
+            TTL: 60,
+            data: data.to_owned(),
+        };
+        let answers = [
+            answer(5, "internal.example.com."),
+            answer(RTYPE_A, "93.184.216.34"),
+            answer(RTYPE_AAAA, "::1"),
+        ];
+        let mut rebinding = Rebinding::new();
+        let check = |rebinding: &Rebinding, name, answers: &[DnsAnswer]| {
+            rebinding.filter(name, answers.to_vec())
+        };
+        assert_eq!(
+            check(&rebinding, "www.example.com.", &answers)
+                .unwrap()
+                .len(),
+            3
+        );
+        rebinding.enabled = true;
+        match check(&rebinding, "WWW.example.com.", &answers) {
+            Err(DnsError::PrivateAddress(name, ip)) => {
+                assert_eq!(name, "www.example.com");
+                assert_eq!(ip, "::1".parse::<IpAddr>().unwrap());
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert!(check(&rebinding, "example.com.", &answers[..2]).is_ok());
+        rebinding.allow("example.com".to_owned());
+        assert_eq!(
+            check(&rebinding, "www.example.com.", &answers)
+                .unwrap()
+                .len(),
+            3
+        );
+        rebinding.disallow("example.com");
+        assert!(check(&rebinding, "www.example.com.", &answers).is_err());
+
+        // The private addresses are dropped and the other records kept.
+        rebinding.response = RebindingResponse::Drop;
+        let kept = check(&rebinding, "www.example.com.", &answers).unwrap();
+        assert_eq!(kept.len(), 2);
+        assert!(kept.iter().all(|a| a.data != "::1"));
+    }
+}
@@ -0,0 +1,162 @@
+//! A resolver for hyper clients that looks up names over DoH instead of the system.
+use crate::client::DnsClient;
+use crate::error::DnsError;
+use crate::Dns;
+use futures_util::future::{self, BoxFuture, FutureExt};
+use hyper::client::connect::dns::Name;
+use std::{
+    net::IpAddr,
+    sync::Arc,
+    task::{self, Poll},
+    vec,
+};
+use tower_service::Service;
+
+/// Resolves the names of outgoing connections with [Dns] by querying their `A` and `AAAA`
+/// records at once. It is given to `hyper::client::HttpConnector::new_with_resolver` so
+/// that a hyper client does not use the system resolver:
+///
+/// ```no_run
+/// # #[cfg(not(feature = "native-tls"))]
+/// # use doh_dns::client::RustlsDnsClient as HyperDnsClient;
+/// # #[cfg(feature = "native-tls")]
+/// use doh_dns::client::HyperDnsClient;
+/// use doh_dns::{resolver::DnsResolver, Dns};
+/// use hyper::client::HttpConnector;
+///
+/// let dns: Dns<HyperDnsClient> = Dns::default();
+/// let connector = HttpConnector::new_with_resolver(DnsResolver::new(dns));
+/// let client = hyper::Client::builder().build::<_, hyper::Body>(connector);
+/// ```
+///
+/// An error is only returned if both queries fail. Cloning the resolver shares the same
+/// [Dns] and its connections to the servers.
+pub struct DnsResolver<C: DnsClient> {
+    dns: Arc<Dns<C>>,
+}
+
+impl<C: DnsClient> DnsResolver<C> {
+    /// Creates a resolver that queries the given [Dns].
+    pub fn new(dns: Dns<C>) -> DnsResolver<C> {
+        DnsResolver { dns: Arc::new(dns) }
+    }
+}
+
+impl<C: DnsClient> From<Arc<Dns<C>>> for DnsResolver<C> {
+    fn from(dns: Arc<Dns<C>>) -> DnsResolver<C> {
+        DnsResolver { dns }
+    }
+}
+
+impl<C: DnsClient> Clone for DnsResolver<C> {
+    fn clone(&self) -> DnsResolver<C> {
+        DnsResolver {
+            dns: Arc::clone(&self.dns),
+        }
+    }
+}
+
+impl<C: DnsClient + Send + Sync + 'static> Service<Name> for DnsResolver<C> {
+    type Response = DnsAddrs;
+    type Error = DnsError;
+    type Future = BoxFuture<'static, Result<DnsAddrs, DnsError>>;
+
+    fn poll_ready(&mut self, _cx: &mut task::Context<'_>) -> Poll<Result<(), DnsError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let dns = Arc::clone(&self.dns);
+        async move {
+            let name = name.as_str();
+            let (a, aaaa) = future::join(dns.resolve_a(name), dns.resolve_aaaa(name)).await;
+            let mut addrs = Vec::new();
+            let mut error = None;
+            for result in [a, aaaa] {
+                match result {
+                    Ok(answers) => {
+                        addrs.extend(answers.iter().filter_map(|a| a.data.parse::<IpAddr>().ok()))
+                    }
+                    Err(e) => error = error.or(Some(e)),
+                }
+            }
+            match error {
+                Some(e) if addrs.is_empty() => Err(e),
+                _ => Ok(DnsAddrs {
+                    inner: addrs.into_iter(),
+                }),
+            }
+        }
+        .boxed()
+    }
+}
+
+/// The addresses found by a [DnsResolver], the IPv4 ones first.
+pub struct DnsAddrs {
+    inner: vec::IntoIter<IpAddr>,
+}
+
+impl Iterator for DnsAddrs {
+    type Item = IpAddr;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::dns::tests::MockDnsClient;
+    use crate::status::RCode;
+    use crate::DnsHttpsServer;
+    use hyper::StatusCode;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    fn resolver(responses: &[(String, StatusCode)]) -> DnsResolver<MockDnsClient> {
+        DnsResolver::new(Dns::new(
+            MockDnsClient::new(responses),
+            vec![DnsHttpsServer::Google(Duration::from_secs(5))],
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let a = r#"{"Status": 0, "Answer": [
+          {"name": "www.example.com.", "type": 5, "TTL": 300, "data": "example.com."},
+          {"name": "example.com.", "type": 1, "TTL": 300, "data": "93.184.216.34"}
+        ]}"#;
+        let aaaa = r#"{"Status": 0, "Answer": [
+          {"name": "www.example.com.", "type": 5, "TTL": 300, "data": "example.com."},
+          {"name": "example.com.", "type": 28, "TTL": 300, "data": "2606:2800:220:1::1"}
+        ]}"#;
+        let mut r = resolver(&[
+            (a.to_owned(), StatusCode::OK),
+            (aaaa.to_owned(), StatusCode::OK),
+        ]);
+        let name = Name::from_str("www.example.com").unwrap();
+        let addrs = r.call(name.clone()).await.unwrap().collect::<Vec<_>>();
+        assert_eq!(
+            addrs,
+            vec![
+                "93.184.216.34".parse::<IpAddr>().unwrap(),
+                "2606:2800:220:1::1".parse().unwrap()
+            ]
+        );
+
+        // The addresses found are returned even if the other query fails.
+        let mut r = resolver(&[
+            (a.to_owned(), StatusCode::OK),
+            (String::new(), StatusCode::BAD_REQUEST),
+        ]);
+        assert_eq!(r.call(name.clone()).await.unwrap().count(), 1);
+
+        let nx = r#"{"Status": 3}"#.to_owned();
+        let mut r = resolver(&[(nx.clone(), StatusCode::OK), (nx, StatusCode::OK)]);
+        match r.call(name).await {
+            Err(DnsError::Status(RCode::NXDomain, _)) => {}
+            r => panic!("unexpected result: {:?}", r.map(|a| a.collect::<Vec<_>>())),
+        }
+    }
+}
@@ -0,0 +1,387 @@
+//! A DoH server that answers queries with a [Dns], so that internal clients reach a single
+//! endpoint while the upstream servers are chosen in its configuration.
+use crate::client::DnsClient;
+use crate::dns::rtype_number;
+use crate::error::{DnsError, QueryError};
+use crate::proxy::percent_decode;
+use crate::stub::StubServer;
+use crate::wire::Message;
+use crate::Dns;
+use hyper::{
+    body::HttpBody,
+    header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use log::error;
+use serde_json::json;
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+// The media type of DNS messages in wire format (RFC 8484).
+const DNS_MESSAGE: &str = "application/dns-message";
+// The media type of the JSON API.
+const DNS_JSON: &str = "application/dns-json";
+// The largest DNS message that can be received.
+const MAX_MESSAGE_SIZE: usize = 65535;
+
+/// Serves the DoH endpoints answered with a [Dns], with its failover and timeouts:
+///
+/// - `GET /resolve?name=<name>&type=<type>` answers with JSON in the format of Google's
+///   JSON API. The type is given by its name or number and defaults to `A`.
+/// - `GET /dns-query?dns=<message>` and `POST /dns-query` answer with a DNS message in
+///   wire format as described in RFC 8484.
+///
+/// Responses have a `Cache-Control` max age of the lowest TTL of their answers. The server
+/// is plain HTTP, with HTTP/1.1 or HTTP/2 with prior knowledge, so TLS is expected to be
+/// terminated in front of it. [DohServer::handle] can also be called from another hyper
+/// service.
+///
+/// ```no_run
+/// # #[cfg(not(feature = "native-tls"))]
+/// # use doh_dns::client::RustlsDnsClient as HyperDnsClient;
+/// # #[cfg(feature = "native-tls")]
+/// use doh_dns::client::HyperDnsClient;
+/// use doh_dns::{server::DohServer, Dns};
+///
+/// # async fn serve() -> Result<(), hyper::Error> {
+/// let dns: Dns<HyperDnsClient> = Dns::default();
+/// DohServer::new(dns).serve("127.0.0.1:8053".parse().unwrap()).await
+/// # }
+/// ```
+pub struct DohServer<C: DnsClient> {
+    dns: Arc<Dns<C>>,
+}
+
+impl<C: DnsClient> DohServer<C> {
+    /// Creates a server that resolves queries with the given [Dns].
+    pub fn new(dns: Dns<C>) -> DohServer<C> {
+        DohServer { dns: Arc::new(dns) }
+    }
+}
+
+impl<C: DnsClient> From<Arc<Dns<C>>> for DohServer<C> {
+    fn from(dns: Arc<Dns<C>>) -> DohServer<C> {
+        DohServer { dns }
+    }
+}
+
+impl<C: DnsClient> Clone for DohServer<C> {
+    fn clone(&self) -> DohServer<C> {
+        DohServer {
+            dns: Arc::clone(&self.dns),
+        }
+    }
+}
+
+impl<C: DnsClient + Send + Sync + 'static> DohServer<C> {
+    /// Listens on the given address and serves the DoH endpoints until the server fails.
+    pub async fn serve(&self, addr: SocketAddr) -> Result<(), hyper::Error> {
+        let server = self.clone();
+        let make_service = make_service_fn(move |_| {
+            let server = server.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let server = server.clone();
+                    async move { Ok::<_, Infallible>(server.handle(req).await) }
+                }))
+            }
+        });
+        Server::try_bind(&addr)?.serve(make_service).await
+    }
+
+    /// Answers a request to one of the DoH endpoints. Other paths are not found.
+    pub async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        match (req.method(), req.uri().path()) {
+            (&Method::GET, "/resolve") => self.resolve_json(req.uri().query()).await,
+            (&Method::GET, "/dns-query") => {
+                let message = query_param(req.uri().query(), "dns")
+                    .and_then(|m| base64::decode_config(&m, base64::URL_SAFE_NO_PAD).ok());
+                match message {
+                    Some(message) => self.resolve_wire(&message).await,
+                    None => error_response(StatusCode::BAD_REQUEST, "invalid dns parameter"),
+                }
+            }
+            (&Method::POST, "/dns-query") => {
+                let content_type = req.headers().get(CONTENT_TYPE);
+                if content_type.map(|t| t.as_bytes()) != Some(DNS_MESSAGE.as_bytes()) {
+                    return error_response(
+                        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                        "expected application/dns-message",
+                    );
+                }
+                match read_message(req.into_body()).await {
+                    Ok(message) => self.resolve_wire(&message).await,
+                    Err(status) => error_response(status, "invalid message"),
+                }
+            }
+            (_, "/resolve") | (_, "/dns-query") => {
+                error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+            }
+            _ => error_response(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+
+    // Answers a query given in the parameters of the JSON API. Upstream failures are given
+    // with a server failure status like Google does.
+    async fn resolve_json(&self, query: Option<&str>) -> Response<Body> {
+        let name = match query_param(query, "name") {
+            Some(name) if !name.is_empty() => name,
+            _ => return error_response(StatusCode::BAD_REQUEST, "missing name parameter"),
+        };
+        let rtype = match query_param(query, "type") {
+            None => 1,
+            Some(rtype) => match rtype_number(&rtype) {
+                Some(rtype) => rtype,
+                None => return error_response(StatusCode::BAD_REQUEST, "invalid type parameter"),
+            },
+        };
+        let question = json!([{ "name": name, "type": rtype }]);
+        let (body, ttls) = match self.dns.query(&name, rtype).await {
+            Ok(res) => {
+                let ttls = res
+                    .Answer
+                    .iter()
+                    .flatten()
+                    .map(|a| a.TTL)
+                    .collect::<Vec<_>>();
+                let mut body = json!({
+                    "Status": res.Status,
+                    "TC": false,
+                    "RD": true,
+                    "RA": true,
+                    "AD": false,
+                    "CD": false,
+                    "Question": question,
+                });
+                if let Some(answers) = res.Answer {
+                    body["Answer"] = json!(answers);
+                }
+                if let Some(comment) = res.Comment {
+                    body["Comment"] = json!(comment);
+                }
+                (body, ttls)
+            }
+            Err(DnsError::Query(QueryError::InvalidName(_))) => {
+                return error_response(StatusCode::BAD_REQUEST, "invalid name parameter")
+            }
+            Err(e) => {
+                // Refused names keep their status, other errors are upstream failures.
+                let status = match e {
+                    DnsError::Status(code, _) => u16::from(code),
+                    _ => {
+                        error!("error resolving {}: {}", name, e);
+                        2
+                    }
+                };
+                let body = json!({
+                    "Status": status,
+                    "TC": false,
+                    "RD": true,
+                    "RA": true,
+                    "AD": false,
+                    "CD": false,
+                    "Question": question,
+                    "Comment": e.to_string(),
+                });
+                (body, vec![])
+            }
+        };
+        dns_response(DNS_JSON, body.to_string().into(), ttls.into_iter().min())
+    }
+
+    // Answers a query given as a message in wire format.
+    async fn resolve_wire(&self, message: &[u8]) -> Response<Body> {
+        let stub = StubServer::from(Arc::clone(&self.dns));
+        match stub.answer(message, false).await {
+            Some(response) => {
+                let min_ttl = Message::from_bytes(&response)
+                    .ok()
+                    .and_then(|m| m.answers.iter().map(|a| a.ttl).min());
+                dns_response(DNS_MESSAGE, response.into(), min_ttl)
+            }
+            None => error_response(StatusCode::BAD_REQUEST, "invalid message"),
+        }
+    }
+}
+
+// Finds a parameter in a query string and decodes it.
+fn query_param(query: Option<&str>, name: &str) -> Option<String> {
+    query?.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        if percent_decode(key) == name {
+            Some(percent_decode(&value.replace('+', " ")))
+        } else {
+            None
+        }
+    })
+}
+
+// Reads a DNS message from the body of a request, failing if it is too large to be one.
+async fn read_message(mut body: Body) -> Result<Vec<u8>, StatusCode> {
+    let mut message = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if message.len() + chunk.len() > MAX_MESSAGE_SIZE {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        message.extend_from_slice(&chunk);
+    }
+    Ok(message)
+}
+
+fn dns_response(content_type: &'static str, body: Body, max_age: Option<u32>) -> Response<Body> {
+    let mut response = Response::new(body);
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    if let Some(max_age) = max_age {
+        if let Ok(value) = HeaderValue::from_str(&format!("max-age={}", max_age)) {
+            response.headers_mut().insert(CACHE_CONTROL, value);
+        }
+    }
+    response
+}
+
+fn error_response(status: StatusCode, message: &'static str) -> Response<Body> {
+    let mut response = Response::new(Body::from(message));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+    response
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::dns::tests::MockDnsClient;
+    use crate::wire::Question;
+    use crate::DnsHttpsServer;
+    use std::time::Duration;
+
+    const RESPONSE: &str = r#"{"Status": 0, "Answer": [
+      {"name": "www.example.com.", "type": 5, "TTL": 300, "data": "example.com."},
+      {"name": "example.com.", "type": 1, "TTL": 60, "data": "93.184.216.34"}
+    ]}"#;
+
+    fn server(responses: &[(String, StatusCode)]) -> DohServer<MockDnsClient> {
+        DohServer::new(Dns::new(
+            MockDnsClient::new(responses),
+            vec![DnsHttpsServer::Google(Duration::from_secs(5))],
+        ))
+    }
+
+    fn request(method: Method, uri: &str, body: Vec<u8>) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .body(body.into())
+            .unwrap()
+    }
+
+    fn query() -> Vec<u8> {
+        Message {
+            id: 0,
+            flags: 0x0100,
+            questions: vec![Question {
+                name: "www.example.com.".to_owned(),
+                qtype: 1,
+                qclass: 1,
+            }],
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
+        }
+        .to_bytes()
+        .unwrap()
+    }
+
+    async fn body(response: Response<Body>) -> Vec<u8> {
+        hyper::body::to_bytes(response).await.unwrap().to_vec()
+    }
+
+    #[tokio::test]
+    async fn test_resolve_json() {
+        let s = server(&[(RESPONSE.to_owned(), StatusCode::OK)]);
+        let res = s
+            .handle(request(
+                Method::GET,
+                "/resolve?name=www.example.com&type=a",
+                vec![],
+            ))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CONTENT_TYPE], DNS_JSON);
+        assert_eq!(res.headers()[CACHE_CONTROL], "max-age=60");
+        let json: serde_json::Value = serde_json::from_slice(&body(res).await).unwrap();
+        assert_eq!(json["Status"], 0);
+        assert_eq!(json["Question"][0]["type"], 1);
+        assert_eq!(json["Answer"][1]["data"], "93.184.216.34");
+        assert_eq!(json["Answer"][1]["type"], 1);
+
+        // Upstream failures are server failures.
+        let s = server(&[(String::new(), StatusCode::BAD_REQUEST)]);
+        let res = s
+            .handle(request(Method::GET, "/resolve?name=example.com", vec![]))
+            .await;
+        let json: serde_json::Value = serde_json::from_slice(&body(res).await).unwrap();
+        assert_eq!(json["Status"], 2);
+
+        let s = server(&[]);
+        for uri in &["/resolve", "/resolve?name=example.com&type=bogus"] {
+            let res = s.handle(request(Method::GET, uri, vec![])).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        }
+        let res = s.handle(request(Method::GET, "/", vec![])).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = s.handle(request(Method::PUT, "/resolve", vec![])).await;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_wire() {
+        let s = server(&vec![(RESPONSE.to_owned(), StatusCode::OK); 2]);
+        let uri = format!(
+            "/dns-query?dns={}",
+            base64::encode_config(query(), base64::URL_SAFE_NO_PAD)
+        );
+        let res = s.handle(request(Method::GET, &uri, vec![])).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CONTENT_TYPE], DNS_MESSAGE);
+        assert_eq!(res.headers()[CACHE_CONTROL], "max-age=60");
+        let m = Message::from_bytes(&body(res).await).unwrap();
+        assert_eq!(m.answers.len(), 2);
+
+        let res = s.handle(request(Method::POST, "/dns-query", query())).await;
+        let m = Message::from_bytes(&body(res).await).unwrap();
+        assert_eq!(m.answers[1].rdata, vec![93, 184, 216, 34]);
+
+        let res = s
+            .handle(request(Method::GET, "/dns-query?dns=%%%", vec![]))
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = s
+            .handle(request(Method::POST, "/dns-query", vec![0; 5]))
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = s
+            .handle(request(Method::POST, "/dns-query", vec![0; 70000]))
+            .await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let mut req = request(Method::POST, "/dns-query", query());
+        req.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        let res = s.handle(req).await;
+        assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[test]
+    fn test_query_param() {
+        let query = Some("name=www.example.com&type=AAAA&x=a%20b+c");
+        assert_eq!(query_param(query, "type"), Some("AAAA".to_owned()));
+        assert_eq!(query_param(query, "x"), Some("a b c".to_owned()));
+        assert_eq!(query_param(query, "dns"), None);
+        assert_eq!(query_param(None, "name"), None);
+    }
+}
@@ -1,89 +1,244 @@
 //! Status codes returned from the DNS over HTTPS server.
 use std::fmt;
-/// These codes were obtained from
-/// <https://www.iana.org/assignments/dns-parameters/dns-parameters.xhtml#dns-parameters-6>.
-#[derive(Debug, FromPrimitive)]
-pub enum RCode {
+
+// Defines an enum of codes with their numeric values and descriptions. Values that are not
+// in the table are kept in the given catch-all variant so they can be converted back.
+macro_rules! codes {
+    (
+        $(#[$enum_docs:meta])*
+        $name:ident, $other:ident;
+        $(
+            $(#[$docs:meta])*
+            ($variant:ident, $num:expr, $text:expr);
+        )+
+    ) => {
+        $(#[$enum_docs])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum $name {
+            $(
+                $(#[$docs])*
+                $variant,
+            )+
+            /// A code that is not assigned in the table above. It keeps the numeric value.
+            $other(u16),
+        }
+
+        impl From<u16> for $name {
+            fn from(code: u16) -> $name {
+                match code {
+                    $(
+                    $num => $name::$variant,
+                    )+
+                    _ => $name::$other(code),
+                }
+            }
+        }
+
+        impl From<$name> for u16 {
+            fn from(code: $name) -> u16 {
+                match code {
+                    $(
+                    $name::$variant => $num,
+                    )+
+                    $name::$other(code) => code,
+                }
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                match *self {
+                    $(
+                    $name::$variant => write!(f, $text),
+                    )+
+                    $name::$other(code) => write!(f, "{} ({})", stringify!($other), code),
+                }
+            }
+        }
+    }
+}
+
+codes! {
+    /// These codes were obtained from
+    /// <https://www.iana.org/assignments/dns-parameters/dns-parameters.xhtml#dns-parameters-6>.
+    /// Codes above 15 only fit in a response that has an EDNS(0) OPT record, which holds
+    /// the upper 8 bits of the 12 bit code. See [RCode::from_parts].
+    RCode, Unknown;
     /// No Error.
-    NoError,
+    (NoError, 0, "No Error");
     /// Format Error.
-    FormErr,
+    (FormErr, 1, "Format Error");
     /// Server Failure.
-    ServFail,
+    (ServFail, 2, "Server Failure");
     /// Non-Existent Domain.
-    NXDomain,
+    (NXDomain, 3, "Non-Existent Domain");
     /// Not Implemented. Cloudflare returns this for all `ANY` DNS requests.
-    NotImp,
+    (NotImp, 4, "Not Implemented");
     /// Query Refused.
-    Refused,
+    (Refused, 5, "Query Refused");
     /// Name Exists when it should not.
-    YXDomain,
+    (YXDomain, 6, "Name Exists when it should not");
     /// RR Set Exists when it should not.
-    YXRRSet,
+    (YXRRSet, 7, "RR Set Exists when it should not");
     /// RR Set that should exist does not.
-    NXRRSet,
+    (NXRRSet, 8, "RR Set that should exist does not");
     /// Server Not Authoritative for zone.
-    NotAuth,
+    (NotAuth, 9, "Server Not Authoritative for zone");
     /// Name not contained in zone.
-    NotZone,
+    (NotZone, 10, "Name not contained in zone");
     /// DSO-TYPE Not Implemented.
-    DSOTYPENI,
-    /// Unassigned.
-    Unassigned12,
-    /// Unassigned.
-    Unassigned13,
-    /// Unassigned.
-    Unassigned14,
-    /// Unassigned.
-    Unassigned15,
-    /// Bad OPT Version.
-    BADVERS,
+    (DSOTYPENI, 11, "DSO-TYPE Not Implemented");
+    /// Bad OPT Version. The same value is used by TSIG for BADSIG, a signature failure.
+    (BADVERS, 16, "Bad OPT Version or TSIG Signature Failure");
     /// Key not recognized.
-    BADKEY,
+    (BADKEY, 17, "Key not recognized");
     /// Signature out of time window.
-    BADTIME,
+    (BADTIME, 18, "Signature out of time window");
     /// Bad TKEY Mode.
-    BADMODE,
+    (BADMODE, 19, "Bad TKEY Mode");
     /// Duplicate key name.
-    BADNAME,
+    (BADNAME, 20, "Duplicate key name");
     /// Algorithm not supported.
-    BADALG,
+    (BADALG, 21, "Algorithm not supported");
     /// Bad Truncation.
-    BADTRUNC,
+    (BADTRUNC, 22, "Bad Truncation");
     /// Bad/missing Server Cookie.
-    BADCOOKIE,
-    /// Unknown.
-    Unknown,
+    (BADCOOKIE, 23, "Bad/missing Server Cookie");
+}
+
+impl RCode {
+    /// Combines the 4 bit RCODE of a message header with the upper 8 bits found in the TTL
+    /// of an EDNS(0) OPT record into the full 12 bit code.
+    pub fn from_parts(header: u8, extended: u8) -> RCode {
+        RCode::from(((extended as u16) << 4) | (header & 0x0f) as u16)
+    }
+
+    /// Returns the lower 4 bits of the code that go in a message header.
+    pub fn header_bits(self) -> u8 {
+        (u16::from(self) & 0x0f) as u8
+    }
+
+    /// Returns the upper 8 bits of the code that go in an EDNS(0) OPT record.
+    pub fn extended_bits(self) -> u8 {
+        ((u16::from(self) >> 4) & 0xff) as u8
+    }
+}
+
+codes! {
+    /// Extended DNS Error codes defined by RFC 8914. These codes were obtained from
+    /// <https://www.iana.org/assignments/dns-parameters/dns-parameters.xhtml#extended-dns-error-codes>.
+    ExtendedErrorCode, Unassigned;
+    /// Other Error.
+    (Other, 0, "Other Error");
+    /// Unsupported DNSKEY Algorithm.
+    (UnsupportedDnskeyAlgorithm, 1, "Unsupported DNSKEY Algorithm");
+    /// Unsupported DS Digest Type.
+    (UnsupportedDsDigestType, 2, "Unsupported DS Digest Type");
+    /// Stale Answer.
+    (StaleAnswer, 3, "Stale Answer");
+    /// Forged Answer.
+    (ForgedAnswer, 4, "Forged Answer");
+    /// DNSSEC Indeterminate.
+    (DnssecIndeterminate, 5, "DNSSEC Indeterminate");
+    /// DNSSEC Bogus.
+    (DnssecBogus, 6, "DNSSEC Bogus");
+    /// Signature Expired.
+    (SignatureExpired, 7, "Signature Expired");
+    /// Signature Not Yet Valid.
+    (SignatureNotYetValid, 8, "Signature Not Yet Valid");
+    /// DNSKEY Missing.
+    (DnskeyMissing, 9, "DNSKEY Missing");
+    /// RRSIGs Missing.
+    (RrsigsMissing, 10, "RRSIGs Missing");
+    /// No Zone Key Bit Set.
+    (NoZoneKeyBitSet, 11, "No Zone Key Bit Set");
+    /// NSEC Missing.
+    (NsecMissing, 12, "NSEC Missing");
+    /// Cached Error.
+    (CachedError, 13, "Cached Error");
+    /// Not Ready.
+    (NotReady, 14, "Not Ready");
+    /// Blocked.
+    (Blocked, 15, "Blocked");
+    /// Censored.
+    (Censored, 16, "Censored");
+    /// Filtered.
+    (Filtered, 17, "Filtered");
+    /// Prohibited.
+    (Prohibited, 18, "Prohibited");
+    /// Stale NXDomain Answer.
+    (StaleNxdomainAnswer, 19, "Stale NXDomain Answer");
+    /// Not Authoritative.
+    (NotAuthoritative, 20, "Not Authoritative");
+    /// Not Supported.
+    (NotSupported, 21, "Not Supported");
+    /// No Reachable Authority.
+    (NoReachableAuthority, 22, "No Reachable Authority");
+    /// Network Error.
+    (NetworkError, 23, "Network Error");
+    /// Invalid Data.
+    (InvalidData, 24, "Invalid Data");
+    /// Signature Expired before Valid.
+    (SignatureExpiredBeforeValid, 25, "Signature Expired before Valid");
+    /// Too Early.
+    (TooEarly, 26, "Too Early");
+    /// Unsupported NSEC3 Iterations Value.
+    (UnsupportedNsec3IterationsValue, 27, "Unsupported NSEC3 Iterations Value");
+    /// Unable to conform to policy.
+    (UnableToConformToPolicy, 28, "Unable to conform to policy");
+    /// Synthesized.
+    (Synthesized, 29, "Synthesized");
+}
+
+/// An Extended DNS Error (RFC 8914) returned by a server to explain a response, such as
+/// the reason of a `ServFail`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtendedError {
+    /// The code of the error.
+    pub code: ExtendedErrorCode,
+    /// Additional text given by the server, if any.
+    pub extra_text: Option<String>,
 }
 
-impl fmt::Display for RCode {
+impl fmt::Display for ExtendedError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match *self {
-            RCode::NoError => write!(f, "No Error"),
-            RCode::FormErr => write!(f, "Format Error"),
-            RCode::ServFail => write!(f, "Server Failure"),
-            RCode::NXDomain => write!(f, "Non-Existent Domain"),
-            RCode::NotImp => write!(f, "Not Implemented"),
-            RCode::Refused => write!(f, "Query Refused"),
-            RCode::YXDomain => write!(f, "Name Exists when it should not"),
-            RCode::YXRRSet => write!(f, "RR Set Exists when it should not"),
-            RCode::NXRRSet => write!(f, "RR Set that should exist does not"),
-            RCode::NotAuth => write!(f, "Server Not Authoritative for zone"),
-            RCode::NotZone => write!(f, "Name not contained in zone"),
-            RCode::DSOTYPENI => write!(f, "DSO-TYPE Not Implemented"),
-            RCode::Unassigned12
-            | RCode::Unassigned13
-            | RCode::Unassigned14
-            | RCode::Unassigned15 => write!(f, "Unassigned"),
-            RCode::BADVERS => write!(f, "Bad OPT Version"),
-            RCode::BADKEY => write!(f, "Key not recognized"),
-            RCode::BADTIME => write!(f, "Signature out of time window"),
-            RCode::BADMODE => write!(f, "Bad TKEY Mode"),
-            RCode::BADNAME => write!(f, "Duplicate key name"),
-            RCode::BADALG => write!(f, "Algorithm not supported"),
-            RCode::BADTRUNC => write!(f, "Bad Truncation"),
-            RCode::BADCOOKIE => write!(f, "Bad/missing Server Cookie"),
-            RCode::Unknown => write!(f, "Unknown"),
+        write!(f, "EDE {}: {}", u16::from(self.code), self.code)?;
+        if let Some(ref text) = self.extra_text {
+            write!(f, ": {}", text)?;
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rcode_conversion() {
+        for code in 0..4096u16 {
+            assert_eq!(u16::from(RCode::from(code)), code);
+        }
+        assert_eq!(RCode::from(3), RCode::NXDomain);
+        assert_eq!(RCode::from(16), RCode::BADVERS);
+        assert_eq!(RCode::from(12), RCode::Unknown(12));
+        assert_eq!(RCode::from(3841), RCode::Unknown(3841));
+
+        // BADCOOKIE (23) is split into 7 in the header and 1 in the OPT record.
+        assert_eq!(RCode::from_parts(7, 1), RCode::BADCOOKIE);
+        assert_eq!(RCode::BADCOOKIE.header_bits(), 7);
+        assert_eq!(RCode::BADCOOKIE.extended_bits(), 1);
+        assert_eq!(RCode::from_parts(0x13, 0), RCode::NXDomain);
+    }
+
+    #[test]
+    fn test_extended_error_code_conversion() {
+        assert_eq!(ExtendedErrorCode::from(15), ExtendedErrorCode::Blocked);
+        assert_eq!(u16::from(ExtendedErrorCode::Blocked), 15);
+        assert_eq!(
+            ExtendedErrorCode::from(500),
+            ExtendedErrorCode::Unassigned(500)
+        );
+        assert_eq!(u16::from(ExtendedErrorCode::Unassigned(500)), 500);
     }
 }
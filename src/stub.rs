@@ -0,0 +1,500 @@
+//! A stub resolver that answers DNS queries received over UDP and TCP, such as those of
+//! the system resolver, by forwarding them to the DoH servers of a [Dns].
+use crate::client::DnsClient;
+use crate::error::DnsError;
+use crate::status::{ExtendedError, RCode};
+use crate::wire::{self, Message, Question, Record, RTYPE_OPT};
+use crate::Dns;
+use futures_util::future;
+use log::error;
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{Mutex, Semaphore},
+    time::timeout,
+};
+
+// The maximum size of a UDP response to a query without EDNS(0).
+const MAX_UDP_SIZE: usize = 512;
+// The UDP payload size advertised in responses, which is also the largest honored. It is
+// the size recommended by the DNS Flag Day 2020 to avoid fragmentation.
+const EDNS_UDP_SIZE: u16 = 1232;
+// The default number of queries received over UDP that are resolved at once.
+const MAX_UDP_QUERIES: usize = 256;
+// How long a TCP connection is kept open waiting for the next query.
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+// The class of Internet records, the only one queried.
+const CLASS_IN: u16 = 1;
+
+// Header flags.
+const FLAG_QR: u16 = 0x8000;
+const FLAG_TC: u16 = 0x0200;
+const FLAG_RD: u16 = 0x0100;
+const FLAG_RA: u16 = 0x0080;
+const OPCODE_MASK: u16 = 0x7800;
+
+/// Listens for DNS queries on UDP and TCP and resolves them with [Dns], with its failover
+/// and timeouts. The records returned by the servers are encoded back in the response and
+/// UDP responses larger than the size allowed by the client are truncated with the TC bit
+/// set so that the query is retried over TCP:
+///
+/// ```no_run
+/// # #[cfg(not(feature = "native-tls"))]
+/// # use doh_dns::client::RustlsDnsClient as HyperDnsClient;
+/// # #[cfg(feature = "native-tls")]
+/// use doh_dns::client::HyperDnsClient;
+/// use doh_dns::{stub::StubServer, Dns};
+///
+/// # async fn serve() -> std::io::Result<()> {
+/// let dns: Dns<HyperDnsClient> = Dns::default();
+/// StubServer::new(dns).serve("127.0.0.1:53".parse().unwrap()).await
+/// # }
+/// ```
+///
+/// Only queries of the `IN` class are answered. Records are encoded from the data returned
+/// by the JSON API, which is only supported for the most common record types. Records of
+/// other types are left out of the response.
+pub struct StubServer<C: DnsClient> {
+    dns: Arc<Dns<C>>,
+    max_udp_queries: usize,
+}
+
+impl<C: DnsClient> StubServer<C> {
+    /// Creates a server that resolves queries with the given [Dns].
+    pub fn new(dns: Dns<C>) -> StubServer<C> {
+        StubServer::from(Arc::new(dns))
+    }
+
+    /// Sets how many queries received over UDP are resolved at once, 256 by default and at
+    /// least 1. Queries received while that many are in flight are dropped, like lost
+    /// datagrams that clients retry, so that a flood of queries does not create unbounded
+    /// tasks and requests to the servers.
+    pub fn set_max_udp_queries(&mut self, max: usize) {
+        self.max_udp_queries = max.max(1);
+    }
+}
+
+impl<C: DnsClient> From<Arc<Dns<C>>> for StubServer<C> {
+    fn from(dns: Arc<Dns<C>>) -> StubServer<C> {
+        StubServer {
+            dns,
+            max_udp_queries: MAX_UDP_QUERIES,
+        }
+    }
+}
+
+impl<C: DnsClient> Clone for StubServer<C> {
+    fn clone(&self) -> StubServer<C> {
+        StubServer {
+            dns: Arc::clone(&self.dns),
+            max_udp_queries: self.max_udp_queries,
+        }
+    }
+}
+
+impl<C: DnsClient + Send + Sync + 'static> StubServer<C> {
+    /// Listens on UDP and TCP at the given address, such as `127.0.0.1:53`, and answers
+    /// queries until either socket fails.
+    pub async fn serve(&self, addr: SocketAddr) -> io::Result<()> {
+        let udp = UdpSocket::bind(addr).await?;
+        let tcp = TcpListener::bind(addr).await?;
+        future::try_join(self.serve_udp(udp), self.serve_tcp(tcp)).await?;
+        Ok(())
+    }
+
+    /// Answers the queries received on a bound UDP socket. Each query is resolved in its
+    /// own task so a slow one does not hold up the others, up to the limit set with
+    /// [StubServer::set_max_udp_queries].
+    pub async fn serve_udp(&self, socket: UdpSocket) -> io::Result<()> {
+        let (mut recv, send) = socket.split();
+        let send = Arc::new(Mutex::new(send));
+        let in_flight = Arc::new(Semaphore::new(self.max_udp_queries));
+        let mut buf = vec![0; u16::MAX as usize];
+        loop {
+            let (len, peer) = recv.recv_from(&mut buf).await?;
+            let permit = match Arc::clone(&in_flight).try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => continue,
+            };
+            let query = buf[..len].to_vec();
+            let server = self.clone();
+            let send = Arc::clone(&send);
+            tokio::spawn(async move {
+                if let Some(response) = server.answer(&query, true).await {
+                    if let Err(e) = send.lock().await.send_to(&response, &peer).await {
+                        error!("error sending response to {}: {}", peer, e);
+                    }
+                }
+                drop(permit);
+            });
+        }
+    }
+
+    /// Answers the queries received on the connections accepted by a TCP listener. Each
+    /// connection is served in its own task and closed once idle.
+    pub async fn serve_tcp(&self, mut listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.serve_connection(stream).await {
+                    error!("error on connection from {}: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    // Answers the queries of a TCP connection in order. Each message is preceded by its
+    // length in two bytes.
+    async fn serve_connection(&self, mut stream: TcpStream) -> io::Result<()> {
+        loop {
+            let mut len = [0; 2];
+            match timeout(TCP_IDLE_TIMEOUT, stream.read_exact(&mut len)).await {
+                Err(_) => return Ok(()),
+                Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Ok(result) => result?,
+            };
+            let mut query = vec![0; u16::from_be_bytes(len) as usize];
+            timeout(TCP_IDLE_TIMEOUT, stream.read_exact(&mut query))
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+            if let Some(response) = self.answer(&query, false).await {
+                let mut message = (response.len() as u16).to_be_bytes().to_vec();
+                message.extend_from_slice(&response);
+                stream.write_all(&message).await?;
+            }
+        }
+    }
+
+    // Builds the response to a query in wire format. Nothing is returned for messages that
+    // are not queries or whose header cannot be read.
+    pub(crate) async fn answer(&self, query: &[u8], udp: bool) -> Option<Vec<u8>> {
+        let query = match Message::from_bytes(query) {
+            Ok(query) => query,
+            // The ID is still known if the header could be read.
+            Err(_) if query.len() >= 12 => {
+                let flags = u16::from_be_bytes([query[2], query[3]]);
+                if flags & FLAG_QR != 0 {
+                    return None;
+                }
+                let response = Message {
+                    id: u16::from_be_bytes([query[0], query[1]]),
+                    flags: FLAG_QR
+                        | (flags & (OPCODE_MASK | FLAG_RD))
+                        | FLAG_RA
+                        | RCode::FormErr.header_bits() as u16,
+                    questions: vec![],
+                    answers: vec![],
+                    authorities: vec![],
+                    additionals: vec![],
+                };
+                return response.to_bytes().ok();
+            }
+            Err(_) => return None,
+        };
+        if query.flags & FLAG_QR != 0 {
+            return None;
+        }
+        let mut response = Message {
+            id: query.id,
+            flags: FLAG_QR | (query.flags & (OPCODE_MASK | FLAG_RD)) | FLAG_RA,
+            questions: query.questions.clone(),
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
+        };
+        let (rcode, extended) = if query.flags & OPCODE_MASK != 0 {
+            (RCode::NotImp, vec![])
+        } else if query.questions.len() != 1 {
+            (RCode::FormErr, vec![])
+        } else {
+            self.resolve(&query.questions[0], &mut response.answers)
+                .await
+        };
+        response.flags |= rcode.header_bits() as u16;
+        // Extended errors, such as for blocked names, are only given to EDNS(0) clients.
+        if query.opt().is_some() {
+            response.additionals.push(Record {
+                name: ".".to_owned(),
+                rtype: RTYPE_OPT,
+                class: EDNS_UDP_SIZE,
+                ttl: (rcode.extended_bits() as u32) << 24,
+                rdata: extended.iter().flat_map(wire::ede_option).collect(),
+            });
+        }
+
+        // The answers are left out of a response that does not fit and the client is
+        // expected to retry over TCP.
+        let max_size = match query.opt() {
+            _ if !udp => u16::MAX as usize,
+            Some(opt) => (opt.class as usize).clamp(MAX_UDP_SIZE, EDNS_UDP_SIZE as usize),
+            None => MAX_UDP_SIZE,
+        };
+        let bytes = response.to_bytes().ok()?;
+        if bytes.len() <= max_size {
+            return Some(bytes);
+        }
+        response.answers.clear();
+        response.flags |= FLAG_TC;
+        response.to_bytes().ok()
+    }
+
+    // Resolves a question adding the records found to the answers and returns the code of
+    // the response with its extended errors.
+    async fn resolve(
+        &self,
+        question: &Question,
+        answers: &mut Vec<Record>,
+    ) -> (RCode, Vec<ExtendedError>) {
+        if question.qclass != CLASS_IN {
+            return (RCode::NotImp, vec![]);
+        }
+        let res = match self.dns.query(&question.name, question.qtype).await {
+            Ok(res) => res,
+            Err(DnsError::InvalidRecordType) => return (RCode::NotImp, vec![]),
+            Err(DnsError::Status(code, extended)) => return (code, extended),
+            Err(e) => {
+                error!("error resolving {}: {}", question.name, e);
+                return (RCode::ServFail, vec![]);
+            }
+        };
+        let extended = res.extended_errors();
+        for answer in res.Answer.unwrap_or_default() {
+            let rtype = answer.r#type as u16;
+            match wire::rdata_from_text(rtype, &answer.data) {
+                Ok(rdata) => answers.push(Record {
+                    name: answer.name,
+                    rtype,
+                    class: CLASS_IN,
+                    ttl: answer.TTL,
+                    rdata,
+                }),
+                Err(e) => error!(
+                    "record of type {} for {} left out: {}",
+                    rtype, answer.name, e
+                ),
+            }
+        }
+        (RCode::from(res.Status), extended)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::dns::tests::MockDnsClient;
+    use crate::status::ExtendedErrorCode;
+    use crate::DnsHttpsServer;
+    use hyper::StatusCode;
+
+    fn server(responses: &[(String, StatusCode)]) -> StubServer<MockDnsClient> {
+        StubServer::new(Dns::new(
+            MockDnsClient::new(responses),
+            vec![DnsHttpsServer::Google(Duration::from_secs(5))],
+        ))
+    }
+
+    fn query(name: &str, qtype: u16, udp_size: Option<u16>) -> Vec<u8> {
+        Message {
+            id: 0x1234,
+            flags: FLAG_RD,
+            questions: vec![Question {
+                name: name.to_owned(),
+                qtype,
+                qclass: CLASS_IN,
+            }],
+            answers: vec![],
+            authorities: vec![],
+            additionals: udp_size
+                .map(|size| Record {
+                    name: ".".to_owned(),
+                    rtype: RTYPE_OPT,
+                    class: size,
+                    ttl: 0,
+                    rdata: vec![],
+                })
+                .into_iter()
+                .collect(),
+        }
+        .to_bytes()
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_answer() {
+        let response = r#"{"Status": 0, "Answer": [
+          {"name": "www.example.com.", "type": 5, "TTL": 300, "data": "example.com."},
+          {"name": "example.com.", "type": 1, "TTL": 60, "data": "93.184.216.34"}
+        ]}"#;
+        let s = server(&[(response.to_owned(), StatusCode::OK)]);
+        let bytes = s.answer(&query("www.example.com.", 1, None), true).await;
+        let m = Message::from_bytes(&bytes.unwrap()).unwrap();
+        assert_eq!(m.id, 0x1234);
+        assert_eq!(m.flags, FLAG_QR | FLAG_RD | FLAG_RA);
+        assert_eq!(m.questions[0].name, "www.example.com.");
+        assert_eq!(m.answers.len(), 2);
+        assert_eq!(m.answers[1].ttl, 60);
+        assert_eq!(m.answers[1].rdata, vec![93, 184, 216, 34]);
+        assert!(m.opt().is_none());
+
+        // The response code of the server is kept.
+        let s = server(&[(r#"{"Status": 3}"#.to_owned(), StatusCode::OK)]);
+        let bytes = s
+            .answer(&query("nx.example.com.", 1, Some(4096)), true)
+            .await;
+        let m = Message::from_bytes(&bytes.unwrap()).unwrap();
+        assert_eq!(m.rcode(), RCode::NXDomain);
+        assert_eq!(m.opt().unwrap().class, EDNS_UDP_SIZE);
+
+        // A failure to reach the servers is a server failure.
+        let s = server(&[(String::new(), StatusCode::BAD_REQUEST)]);
+        let bytes = s.answer(&query("example.com.", 1, None), true).await;
+        assert_eq!(
+            Message::from_bytes(&bytes.unwrap()).unwrap().rcode(),
+            RCode::ServFail
+        );
+
+        // Unknown types are not implemented and responses are not answered.
+        let s = server(&[]);
+        let bytes = s.answer(&query("example.com.", 65280, None), true).await;
+        assert_eq!(
+            Message::from_bytes(&bytes.unwrap()).unwrap().rcode(),
+            RCode::NotImp
+        );
+        let mut response = query("example.com.", 1, None);
+        response[2] |= 0x80;
+        assert_eq!(s.answer(&response, true).await, None);
+        assert_eq!(s.answer(&response[..14], true).await, None);
+        assert_eq!(s.answer(&response[..5], true).await, None);
+
+        // A query that cannot be decoded gets a format error if its header can be read.
+        let bytes = s.answer(&query("example.com.", 1, None)[..14], true).await;
+        let m = Message::from_bytes(&bytes.unwrap()).unwrap();
+        assert_eq!((m.id, m.rcode()), (0x1234, RCode::FormErr));
+    }
+
+    #[tokio::test]
+    async fn test_blocked() {
+        let mut dns = Dns::new(
+            MockDnsClient::new(&[]),
+            vec![DnsHttpsServer::Google(Duration::from_secs(5))],
+        );
+        dns.load_blocklist("ads.example.com");
+        let s = StubServer::new(dns);
+
+        let bytes = s
+            .answer(&query("www.ads.example.com.", 1, Some(1232)), true)
+            .await;
+        let m = Message::from_bytes(&bytes.unwrap()).unwrap();
+        assert_eq!(m.rcode(), RCode::NXDomain);
+        let extended = m.extended_errors();
+        assert_eq!(extended.len(), 1);
+        assert_eq!(extended[0].code, ExtendedErrorCode::Blocked);
+        assert_eq!(extended[0].extra_text, None);
+
+        // Without EDNS(0) only the response code is given.
+        let bytes = s.answer(&query("ads.example.com.", 1, None), true).await;
+        let m = Message::from_bytes(&bytes.unwrap()).unwrap();
+        assert_eq!(m.rcode(), RCode::NXDomain);
+        assert!(m.opt().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_truncation() {
+        let answers = (0..40)
+            .map(|i| {
+                format!(
+                    r#"{{"name": "example.com.", "type": 1, "TTL": 60, "data": "10.0.0.{}"}}"#,
+                    i
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let response = format!(r#"{{"Status": 0, "Answer": [{}]}}"#, answers);
+        let s = server(&vec![(response, StatusCode::OK); 3]);
+
+        let bytes = s.answer(&query("example.com.", 1, None), true).await;
+        let m = Message::from_bytes(&bytes.unwrap()).unwrap();
+        assert_eq!(m.flags & FLAG_TC, FLAG_TC);
+        assert!(m.answers.is_empty());
+        assert_eq!(m.questions.len(), 1);
+
+        // The payload size given with EDNS(0) is honored.
+        let bytes = s.answer(&query("example.com.", 1, Some(1232)), true).await;
+        let m = Message::from_bytes(&bytes.unwrap()).unwrap();
+        assert_eq!(m.flags & FLAG_TC, 0);
+        assert_eq!(m.answers.len(), 40);
+
+        let bytes = s.answer(&query("example.com.", 1, None), false).await;
+        assert_eq!(
+            Message::from_bytes(&bytes.unwrap()).unwrap().answers.len(),
+            40
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let response = r#"{"Status": 0, "Answer": [
+          {"name": "example.com.", "type": 1, "TTL": 60, "data": "93.184.216.34"}
+        ]}"#;
+        let s = server(&vec![(response.to_owned(), StatusCode::OK); 2]);
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp_addr = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tcp_addr = tcp.local_addr().unwrap();
+        let server = s.clone();
+        tokio::spawn(async move { server.serve_udp(udp).await });
+        tokio::spawn(async move { s.serve_tcp(tcp).await });
+
+        let mut client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(udp_addr).await.unwrap();
+        client.send(&query("example.com.", 1, None)).await.unwrap();
+        let mut buf = [0; 512];
+        let len = client.recv(&mut buf).await.unwrap();
+        assert_eq!(Message::from_bytes(&buf[..len]).unwrap().answers.len(), 1);
+
+        let mut stream = TcpStream::connect(tcp_addr).await.unwrap();
+        let query = query("example.com.", 1, None);
+        let mut message = (query.len() as u16).to_be_bytes().to_vec();
+        message.extend_from_slice(&query);
+        stream.write_all(&message).await.unwrap();
+        let mut len = [0; 2];
+        stream.read_exact(&mut len).await.unwrap();
+        let mut response = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(Message::from_bytes(&response).unwrap().answers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_max_udp_queries() {
+        let response = r#"{"Status": 0, "Answer": []}"#;
+        let responses = vec![(response.to_owned(), StatusCode::OK); 3];
+        let mut s = StubServer::new(Dns::new(
+            MockDnsClient::with_delay(&responses, Duration::from_millis(200)),
+            vec![DnsHttpsServer::Google(Duration::from_secs(5))],
+        ));
+        s.set_max_udp_queries(2);
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp_addr = udp.local_addr().unwrap();
+        tokio::spawn(async move { s.serve_udp(udp).await });
+
+        // The queries received while two are in flight are dropped.
+        let mut client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(udp_addr).await.unwrap();
+        for _ in 0..4 {
+            client.send(&query("example.com.", 1, None)).await.unwrap();
+        }
+        let mut buf = [0; 512];
+        for _ in 0..2 {
+            client.recv(&mut buf).await.unwrap();
+        }
+        let wait = Duration::from_millis(400);
+        assert!(timeout(wait, client.recv(&mut buf)).await.is_err());
+
+        // Queries are answered again once the others are done.
+        client.send(&query("example.com.", 1, None)).await.unwrap();
+        assert!(timeout(wait, client.recv(&mut buf)).await.is_ok());
+    }
+}
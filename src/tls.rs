@@ -0,0 +1,351 @@
+// TLS settings shared by the HTTPS clients, such as the verification of pinned keys.
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+use crate::error::DnsError;
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+use std::error::Error;
+#[cfg(feature = "native-tls")]
+use std::fmt;
+
+// Decodes the base64 SHA-256 pins given in the server options.
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+pub(crate) fn decode_pins(pins: &[String]) -> Result<Vec<[u8; 32]>, DnsError> {
+    pins.iter()
+        .map(|pin| {
+            let hash = base64::decode(pin).map_err(|e| DnsError::Client(e.into()))?;
+            if hash.len() != 32 {
+                return Err(DnsError::Client(
+                    format!("pin {} is not a SHA-256 hash", pin).into(),
+                ));
+            }
+            let mut pin = [0; 32];
+            pin.copy_from_slice(&hash);
+            Ok(pin)
+        })
+        .collect()
+}
+
+// Splits a PEM bundle into the PEM blocks of each certificate.
+#[cfg(feature = "native-tls")]
+pub(crate) fn pem_certificates(pem: &[u8]) -> Vec<&[u8]> {
+    const END: &[u8] = b"-----END CERTIFICATE-----";
+    let mut certs = Vec::new();
+    let mut rest = pem;
+    while let Some(start) = find(rest, b"-----BEGIN CERTIFICATE-----") {
+        match find(&rest[start..], END) {
+            Some(end) => {
+                certs.push(&rest[start..start + end + END.len()]);
+                rest = &rest[start + end + END.len()..];
+            }
+            None => break,
+        }
+    }
+    certs
+}
+
+#[cfg(feature = "native-tls")]
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+// The message of the TLS error returned when no pinned key is found. It is used to tell a
+// pin mismatch apart from other connection errors.
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+const PIN_MISMATCH: &str = "no certificate presented by the server matches the pinned keys";
+
+// The error returned by the native-tls connector, which checks the pins after the
+// handshake.
+#[cfg(feature = "native-tls")]
+#[derive(Debug)]
+pub(crate) struct PinMismatch;
+
+#[cfg(feature = "native-tls")]
+impl fmt::Display for PinMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(PIN_MISMATCH)
+    }
+}
+
+#[cfg(feature = "native-tls")]
+impl Error for PinMismatch {}
+
+// Returns true if the connection error was caused by the server not matching its pins.
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+pub(crate) fn is_pin_mismatch(error: &hyper::Error) -> bool {
+    error.source().is_some_and(is_pin_mismatch_source)
+}
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+pub(crate) fn is_pin_mismatch(_error: &hyper::Error) -> bool {
+    false
+}
+
+// The TLS error is wrapped in I/O errors by the connectors, whose sources skip the error
+// they wrap so those are unwrapped explicitly.
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+fn is_pin_mismatch_source(error: &(dyn Error + 'static)) -> bool {
+    #[cfg(feature = "native-tls")]
+    {
+        if error.is::<PinMismatch>() {
+            return true;
+        }
+    }
+    #[cfg(feature = "rustls")]
+    {
+        if let Some(tokio_rustls::rustls::TLSError::General(msg)) = error.downcast_ref() {
+            return msg == PIN_MISMATCH;
+        }
+    }
+    if let Some(inner) = error
+        .downcast_ref::<std::io::Error>()
+        .and_then(|e| e.get_ref())
+    {
+        return is_pin_mismatch_source(inner);
+    }
+    error.source().is_some_and(is_pin_mismatch_source)
+}
+
+// Returns true if the key of the DER encoded certificate is pinned.
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+pub(crate) fn is_pinned(pins: &[[u8; 32]], cert: &[u8]) -> bool {
+    spki_sha256(cert).is_some_and(|hash| pins.contains(&hash))
+}
+
+// Verifies the certificate chain as usual and then checks that a pinned key is in a chain
+// from the server certificate to a trusted root. Since webpki does not return the chain it
+// built, the chain is verified again with only the pinned certificates and roots as trust
+// anchors, unless the server certificate itself is pinned.
+#[cfg(feature = "rustls")]
+pub(crate) struct PinningVerifier {
+    inner: tokio_rustls::rustls::WebPKIVerifier,
+    pins: Vec<[u8; 32]>,
+}
+
+#[cfg(feature = "rustls")]
+impl PinningVerifier {
+    pub(crate) fn new(pins: Vec<[u8; 32]>) -> PinningVerifier {
+        PinningVerifier {
+            inner: tokio_rustls::rustls::WebPKIVerifier::new(),
+            pins,
+        }
+    }
+}
+
+#[cfg(feature = "rustls")]
+impl tokio_rustls::rustls::ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &tokio_rustls::rustls::RootCertStore,
+        presented_certs: &[tokio_rustls::rustls::Certificate],
+        dns_name: tokio_rustls::webpki::DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> Result<tokio_rustls::rustls::ServerCertVerified, tokio_rustls::rustls::TLSError> {
+        let verified =
+            self.inner
+                .verify_server_cert(roots, presented_certs, dns_name, ocsp_response)?;
+        if presented_certs
+            .first()
+            .is_some_and(|cert| is_pinned(&self.pins, &cert.0))
+        {
+            return Ok(verified);
+        }
+        let mut anchors = roots.clone();
+        anchors.roots.retain(|root| {
+            let spki = root.to_trust_anchor().spki;
+            self.pins
+                .contains(&sha256(&[&der_header(0x30, spki.len()), spki]))
+        });
+        for cert in presented_certs.iter().skip(1) {
+            if is_pinned(&self.pins, &cert.0) {
+                // A certificate that is not a valid trust anchor cannot be pinned.
+                let _ = anchors.add(cert);
+            }
+        }
+        if !anchors.is_empty()
+            && self
+                .inner
+                .verify_server_cert(&anchors, presented_certs, dns_name, ocsp_response)
+                .is_ok()
+        {
+            Ok(verified)
+        } else {
+            Err(tokio_rustls::rustls::TLSError::General(
+                PIN_MISMATCH.to_string(),
+            ))
+        }
+    }
+}
+
+// Returns the DER header of an element with the given tag and content length, as trust
+// anchors only keep the content of the SubjectPublicKeyInfo.
+#[cfg(feature = "rustls")]
+fn der_header(tag: u8, len: usize) -> Vec<u8> {
+    let mut header = vec![tag];
+    if len < 0x80 {
+        header.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|&&b| b == 0).count();
+        header.push(0x80 | (bytes.len() - skip) as u8);
+        header.extend_from_slice(&bytes[skip..]);
+    }
+    header
+}
+
+// Returns the SHA-256 hash of the DER encoded SubjectPublicKeyInfo of a certificate.
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+fn spki_sha256(cert: &[u8]) -> Option<[u8; 32]> {
+    Some(sha256(&[spki(cert)?]))
+}
+
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+fn sha256(parts: &[&[u8]]) -> [u8; 32] {
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+    for part in parts {
+        context.update(part);
+    }
+    let mut hash = [0; 32];
+    hash.copy_from_slice(context.finish().as_ref());
+    hash
+}
+
+// Finds the SubjectPublicKeyInfo in a DER encoded X.509 certificate:
+//
+// Certificate ::= SEQUENCE { tbsCertificate TBSCertificate, ... }
+// TBSCertificate ::= SEQUENCE { version [0] EXPLICIT OPTIONAL, serialNumber, signature,
+//                               issuer, validity, subject, subjectPublicKeyInfo, ... }
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+fn spki(cert: &[u8]) -> Option<&[u8]> {
+    let (_, cert, _) = der_element(cert)?;
+    let (_, tbs, _) = der_element(cert)?;
+    let (tag, _, mut rest) = der_element(tbs)?;
+    if tag != 0xa0 {
+        // There is no version so the first element is the serial number.
+        rest = tbs;
+    }
+    // Skip the serial number, signature, issuer, validity and subject.
+    for _ in 0..5 {
+        rest = der_element(rest)?.2;
+    }
+    let (_, content, after) = der_element(rest)?;
+    let header_len = rest.len() - after.len() - content.len();
+    Some(&rest[..header_len + content.len()])
+}
+
+// Reads a DER element returning its tag, its content and the input after it.
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *input.first()?;
+    let first = *input.get(1)? as usize;
+    let (len, header_len) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let count = first & 0x7f;
+        if count == 0 || count > 4 {
+            return None;
+        }
+        let len = input
+            .get(2..2 + count)?
+            .iter()
+            .fold(0, |len, &b| (len << 8) | b as usize);
+        (len, 2 + count)
+    };
+    let content = input.get(header_len..header_len.checked_add(len)?)?;
+    Some((tag, content, &input[header_len + len..]))
+}
+
+#[cfg(all(test, any(feature = "native-tls", feature = "rustls")))]
+pub mod tests {
+    use super::*;
+
+    #[cfg(feature = "native-tls")]
+    #[test]
+    fn test_pem_certificates() {
+        let pem = b"-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n\
+                    # comment\n-----BEGIN CERTIFICATE-----\nMIIC\n-----END CERTIFICATE-----\n";
+        let certs = pem_certificates(pem);
+        assert_eq!(certs.len(), 2);
+        assert!(certs[1].starts_with(b"-----BEGIN CERTIFICATE-----\nMIIC"));
+        assert!(certs[1].ends_with(b"-----END CERTIFICATE-----"));
+        assert!(pem_certificates(b"-----BEGIN CERTIFICATE-----\nMIIB").is_empty());
+    }
+
+    #[test]
+    fn test_decode_pins() {
+        let pins = decode_pins(&["47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=".to_owned()]);
+        assert_eq!(pins.unwrap()[0][..4], [0xe3, 0xb0, 0xc4, 0x42]);
+        assert!(decode_pins(&["not base64".to_owned()]).is_err());
+        assert!(decode_pins(&["AAAA".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn test_spki() {
+        // A minimal certificate structure with a version, 5 fields and the public key.
+        let tbs = [
+            &[0xa0, 0x03, 0x02, 0x01, 0x02][..],
+            &[0x02, 0x01, 0x01],
+            &[0x30, 0x00],
+            &[0x30, 0x00],
+            &[0x30, 0x00],
+            &[0x30, 0x00],
+            &[0x30, 0x03, 0x03, 0x01, 0x00],
+            &[0xa3, 0x00],
+        ]
+        .concat();
+        let mut cert = vec![0x30, tbs.len() as u8 + 2, 0x30, tbs.len() as u8];
+        cert.extend_from_slice(&tbs);
+        assert_eq!(spki(&cert), Some(&[0x30, 0x03, 0x03, 0x01, 0x00][..]));
+        assert_eq!(spki(&cert[..cert.len() - 4]), None);
+    }
+
+    #[cfg(feature = "rustls")]
+    #[test]
+    fn test_pinning_verifier() {
+        use tokio_rustls::rustls::{
+            internal::pemfile, Certificate, RootCertStore, ServerCertVerifier,
+        };
+        use tokio_rustls::webpki::DNSNameRef;
+        let cert = |pem: &[u8]| pemfile::certs(&mut &pem[..]).unwrap().remove(0);
+        let ca = cert(include_bytes!("../testdata/ca.pem"));
+        let server = cert(include_bytes!("../testdata/server.pem"));
+        let other = cert(include_bytes!("../testdata/other.pem"));
+        let mut roots = RootCertStore::empty();
+        roots.add(&ca).unwrap();
+        let name = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let verify = |pinned: &Certificate, chain: &[Certificate]| {
+            let verifier = PinningVerifier::new(vec![spki_sha256(&pinned.0).unwrap()]);
+            verifier.verify_server_cert(&roots, chain, name, &[])
+        };
+        assert!(verify(&server, std::slice::from_ref(&server)).is_ok());
+        assert!(verify(&ca, std::slice::from_ref(&other)).is_ok());
+        assert!(verify(&ca, &[other.clone(), ca.clone()]).is_ok());
+        // A pinned certificate that is not in the chain of the server is ignored.
+        match verify(&server, &[other.clone(), server.clone()]) {
+            Err(tokio_rustls::rustls::TLSError::General(msg)) => assert_eq!(msg, PIN_MISMATCH),
+            r => panic!("unexpected result: {:?}", r.is_ok()),
+        }
+        assert!(verify(&server, &[other]).is_err());
+    }
+
+    #[cfg(feature = "rustls")]
+    #[test]
+    fn test_der_header() {
+        assert_eq!(der_header(0x30, 0x59), [0x30, 0x59]);
+        assert_eq!(der_header(0x30, 0x80), [0x30, 0x81, 0x80]);
+        assert_eq!(der_header(0x30, 0x122), [0x30, 0x82, 0x01, 0x22]);
+    }
+
+    #[cfg(feature = "rustls")]
+    #[test]
+    fn test_pin_mismatch_source() {
+        use std::io;
+        use tokio_rustls::rustls::TLSError;
+        let tls = io::Error::new(
+            io::ErrorKind::InvalidData,
+            TLSError::General(PIN_MISMATCH.to_owned()),
+        );
+        let wrapped = io::Error::other(tls);
+        assert!(is_pin_mismatch_source(&wrapped));
+        let other = io::Error::other(TLSError::NoCertificatesPresented);
+        assert!(!is_pin_mismatch_source(&other));
+    }
+}
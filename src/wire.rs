@@ -0,0 +1,691 @@
+//! DNS messages in wire format as described in RFC 1035 and used by DoH servers for the
+//! `application/dns-message` media type (RFC 8484).
+use crate::status::{ExtendedError, RCode};
+use std::{
+    error::Error,
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+// The record type of the EDNS(0) OPT pseudo-record.
+pub(crate) const RTYPE_OPT: u16 = 41;
+// The EDNS(0) option code of an Extended DNS Error.
+const OPTION_EDE: u16 = 15;
+// Maximum number of compression pointers followed while reading a single name.
+const MAX_POINTERS: usize = 64;
+// The smallest size of a record: a root name and the type, class, TTL and rdata length.
+const MIN_RECORD_SIZE: usize = 11;
+
+/// Errors returned when a DNS message cannot be decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WireError {
+    /// The message ended before all of its content could be read.
+    Truncated,
+    /// A domain name in the message is malformed, such as one with a compression loop or
+    /// a label longer than 63 bytes.
+    InvalidName,
+    /// The data of a record given in presentation format cannot be encoded.
+    InvalidData,
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            WireError::Truncated => write!(f, "truncated DNS message"),
+            WireError::InvalidName => write!(f, "invalid domain name in DNS message"),
+            WireError::InvalidData => write!(f, "invalid record data"),
+        }
+    }
+}
+
+impl Error for WireError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+/// An entry of the question section of a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question {
+    /// The name queried in presentation format with a trailing dot.
+    pub name: String,
+    /// The record type queried.
+    pub qtype: u16,
+    /// The class queried, usually `IN` (1).
+    pub qclass: u16,
+}
+
+/// A resource record of the answer, authority or additional sections of a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// The owner name of the record in presentation format with a trailing dot.
+    pub name: String,
+    /// The record type.
+    pub rtype: u16,
+    /// The class of the record. For OPT records it holds the requestor's UDP payload size.
+    pub class: u16,
+    /// The time to live of the record. For OPT records it holds the extended RCODE and flags.
+    pub ttl: u32,
+    /// The raw record data. Names inside it may contain compression pointers relative to
+    /// the message it was read from.
+    pub rdata: Vec<u8>,
+}
+
+/// A decoded DNS message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// The message ID.
+    pub id: u16,
+    /// The header flags, including the opcode and the lower 4 bits of the RCODE.
+    pub flags: u16,
+    /// The question section.
+    pub questions: Vec<Question>,
+    /// The answer section.
+    pub answers: Vec<Record>,
+    /// The authority section.
+    pub authorities: Vec<Record>,
+    /// The additional section, including the OPT pseudo-record if present.
+    pub additionals: Vec<Record>,
+}
+
+impl Message {
+    /// Decodes a message from its wire format.
+    pub fn from_bytes(buf: &[u8]) -> Result<Message, WireError> {
+        let mut reader = Reader { buf, pos: 0 };
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        let qdcount = reader.u16()?;
+        let ancount = reader.u16()?;
+        let nscount = reader.u16()?;
+        let arcount = reader.u16()?;
+        let mut questions = Vec::new();
+        for _ in 0..qdcount {
+            questions.push(Question {
+                name: reader.name()?,
+                qtype: reader.u16()?,
+                qclass: reader.u16()?,
+            });
+        }
+        Ok(Message {
+            id,
+            flags,
+            questions,
+            answers: reader.records(ancount)?,
+            authorities: reader.records(nscount)?,
+            additionals: reader.records(arcount)?,
+        })
+    }
+
+    /// Encodes the message in wire format. Names are not compressed.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, WireError> {
+        let mut buf = Vec::with_capacity(512);
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.extend_from_slice(&self.flags.to_be_bytes());
+        for count in &[
+            self.questions.len(),
+            self.answers.len(),
+            self.authorities.len(),
+            self.additionals.len(),
+        ] {
+            if *count > u16::MAX as usize {
+                return Err(WireError::InvalidData);
+            }
+            buf.extend_from_slice(&(*count as u16).to_be_bytes());
+        }
+        for question in &self.questions {
+            write_name(&mut buf, &question.name)?;
+            buf.extend_from_slice(&question.qtype.to_be_bytes());
+            buf.extend_from_slice(&question.qclass.to_be_bytes());
+        }
+        for record in self
+            .answers
+            .iter()
+            .chain(&self.authorities)
+            .chain(&self.additionals)
+        {
+            if record.rdata.len() > u16::MAX as usize {
+                return Err(WireError::InvalidData);
+            }
+            write_name(&mut buf, &record.name)?;
+            buf.extend_from_slice(&record.rtype.to_be_bytes());
+            buf.extend_from_slice(&record.class.to_be_bytes());
+            buf.extend_from_slice(&record.ttl.to_be_bytes());
+            buf.extend_from_slice(&(record.rdata.len() as u16).to_be_bytes());
+            buf.extend_from_slice(&record.rdata);
+        }
+        Ok(buf)
+    }
+
+    /// Returns the response code of the message. If the message has an OPT record, its
+    /// upper 8 bits are combined with the 4 bits found in the header.
+    pub fn rcode(&self) -> RCode {
+        let extended = self.opt().map_or(0, |opt| (opt.ttl >> 24) as u8);
+        RCode::from_parts((self.flags & 0x000f) as u8, extended)
+    }
+
+    /// Returns the EDNS(0) OPT pseudo-record of the message if it has one.
+    pub fn opt(&self) -> Option<&Record> {
+        self.additionals.iter().find(|r| r.rtype == RTYPE_OPT)
+    }
+
+    /// Returns the Extended DNS Errors (RFC 8914) found in the OPT pseudo-record.
+    pub fn extended_errors(&self) -> Vec<ExtendedError> {
+        let mut errors = Vec::new();
+        if let Some(opt) = self.opt() {
+            let mut reader = Reader {
+                buf: &opt.rdata,
+                pos: 0,
+            };
+            while let (Ok(code), Ok(len)) = (reader.u16(), reader.u16()) {
+                let data = match reader.bytes(len as usize) {
+                    Ok(data) => data,
+                    Err(_) => break,
+                };
+                if code == OPTION_EDE && data.len() >= 2 {
+                    let text = String::from_utf8_lossy(&data[2..]);
+                    errors.push(ExtendedError {
+                        code: u16::from_be_bytes([data[0], data[1]]).into(),
+                        extra_text: if text.is_empty() {
+                            None
+                        } else {
+                            Some(text.into_owned())
+                        },
+                    });
+                }
+            }
+        }
+        errors
+    }
+}
+
+// Encodes an Extended DNS Error as an option of an OPT record.
+pub(crate) fn ede_option(error: &ExtendedError) -> Vec<u8> {
+    let text = error.extra_text.as_deref().unwrap_or_default().as_bytes();
+    let len = (2 + text.len()).min(u16::MAX as usize);
+    let mut option = Vec::with_capacity(4 + len);
+    option.extend_from_slice(&OPTION_EDE.to_be_bytes());
+    option.extend_from_slice(&(len as u16).to_be_bytes());
+    option.extend_from_slice(&u16::from(error.code).to_be_bytes());
+    option.extend_from_slice(&text[..len - 2]);
+    option
+}
+
+// Reads the different fields of a message keeping track of the position in the buffer.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], WireError> {
+        if self.buf.len() - self.pos < len {
+            return Err(WireError::Truncated);
+        }
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, WireError> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, WireError> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    // Reads a possibly compressed name. The position is left after the name as found in the
+    // buffer, not after the labels pointed to.
+    fn name(&mut self) -> Result<String, WireError> {
+        let mut name = String::new();
+        let mut pos = self.pos;
+        let mut end = None;
+        let mut pointers = 0;
+        loop {
+            let len = *self.buf.get(pos).ok_or(WireError::Truncated)? as usize;
+            match len & 0xc0 {
+                0x00 if len == 0 => {
+                    pos += 1;
+                    break;
+                }
+                0x00 => {
+                    let label = self
+                        .buf
+                        .get(pos + 1..pos + 1 + len)
+                        .ok_or(WireError::Truncated)?;
+                    push_label(&mut name, label);
+                    pos += 1 + len;
+                }
+                0xc0 => {
+                    let low = *self.buf.get(pos + 1).ok_or(WireError::Truncated)? as usize;
+                    pointers += 1;
+                    if pointers > MAX_POINTERS {
+                        return Err(WireError::InvalidName);
+                    }
+                    if end.is_none() {
+                        end = Some(pos + 2);
+                    }
+                    pos = ((len & 0x3f) << 8) | low;
+                }
+                _ => return Err(WireError::InvalidName),
+            }
+        }
+        self.pos = end.unwrap_or(pos);
+        if name.is_empty() {
+            name.push('.');
+        }
+        Ok(name)
+    }
+
+    fn records(&mut self, count: u16) -> Result<Vec<Record>, WireError> {
+        // The counts are not trusted so no more records are allocated than could fit in
+        // the rest of the message.
+        let remaining = self.buf.len().saturating_sub(self.pos);
+        let mut records = Vec::with_capacity((count as usize).min(remaining / MIN_RECORD_SIZE));
+        for _ in 0..count {
+            let name = self.name()?;
+            let rtype = self.u16()?;
+            let class = self.u16()?;
+            let ttl = self.u32()?;
+            let len = self.u16()? as usize;
+            records.push(Record {
+                name,
+                rtype,
+                class,
+                ttl,
+                rdata: self.bytes(len)?.to_vec(),
+            });
+        }
+        Ok(records)
+    }
+}
+
+// Appends a label to the name in presentation format escaping dots, backslashes and
+// non-printable characters.
+fn push_label(name: &mut String, label: &[u8]) {
+    for &b in label {
+        match b {
+            b'.' | b'\\' => {
+                name.push('\\');
+                name.push(b as char);
+            }
+            0x21..=0x7e => name.push(b as char),
+            _ => name.push_str(&format!("\\{:03}", b)),
+        }
+    }
+    name.push('.');
+}
+
+// Writes a name given in presentation format as a sequence of labels. Escaped dots,
+// backslashes and `\DDD` decimal escapes are unescaped.
+fn write_name(buf: &mut Vec<u8>, name: &str) -> Result<(), WireError> {
+    let start = buf.len();
+    let mut label = Vec::new();
+    let mut chars = name.bytes();
+    let mut trailing_dot = false;
+    while let Some(b) = chars.next() {
+        trailing_dot = false;
+        match b {
+            b'.' => {
+                if label.is_empty() {
+                    // Only the root name may have an empty label.
+                    if name != "." {
+                        return Err(WireError::InvalidName);
+                    }
+                    break;
+                }
+                push_wire_label(buf, &label)?;
+                label.clear();
+                trailing_dot = true;
+            }
+            b'\\' => {
+                let escaped = chars.next().ok_or(WireError::InvalidName)?;
+                if escaped.is_ascii_digit() {
+                    let digits = [
+                        escaped,
+                        chars.next().ok_or(WireError::InvalidName)?,
+                        chars.next().ok_or(WireError::InvalidName)?,
+                    ];
+                    let value = std::str::from_utf8(&digits)
+                        .ok()
+                        .and_then(|d| d.parse::<u8>().ok())
+                        .ok_or(WireError::InvalidName)?;
+                    label.push(value);
+                } else {
+                    label.push(escaped);
+                }
+            }
+            _ => label.push(b),
+        }
+    }
+    if !label.is_empty() {
+        push_wire_label(buf, &label)?;
+    } else if !trailing_dot && name != "." && !name.is_empty() {
+        return Err(WireError::InvalidName);
+    }
+    buf.push(0);
+    if buf.len() - start > 255 {
+        return Err(WireError::InvalidName);
+    }
+    Ok(())
+}
+
+fn push_wire_label(buf: &mut Vec<u8>, label: &[u8]) -> Result<(), WireError> {
+    if label.len() > 63 {
+        return Err(WireError::InvalidName);
+    }
+    buf.push(label.len() as u8);
+    buf.extend_from_slice(label);
+    Ok(())
+}
+
+// Encodes the data of a record given in presentation format, as returned by the JSON API,
+// for the most common record types. Other types are only encoded if given in the generic
+// `\# <length> <hex>` format of RFC 3597.
+pub(crate) fn rdata_from_text(rtype: u16, data: &str) -> Result<Vec<u8>, WireError> {
+    let data = data.trim();
+    if let Some(generic) = data.strip_prefix("\\#") {
+        return generic_rdata(generic);
+    }
+    let fields = data.split_whitespace().collect::<Vec<_>>();
+    let mut buf = Vec::new();
+    match (rtype, fields.as_slice()) {
+        (1, [ip]) => {
+            let ip = ip.parse::<Ipv4Addr>().map_err(|_| WireError::InvalidData)?;
+            buf.extend_from_slice(&ip.octets());
+        }
+        (28, [ip]) => {
+            let ip = ip.parse::<Ipv6Addr>().map_err(|_| WireError::InvalidData)?;
+            buf.extend_from_slice(&ip.octets());
+        }
+        // NS, CNAME, PTR and DNAME.
+        (2, [name]) | (5, [name]) | (12, [name]) | (39, [name]) => write_name(&mut buf, name)?,
+        (15, [preference, exchange]) => {
+            buf.extend_from_slice(&number::<u16>(preference)?.to_be_bytes());
+            write_name(&mut buf, exchange)?;
+        }
+        (33, [priority, weight, port, target]) => {
+            for n in &[priority, weight, port] {
+                buf.extend_from_slice(&number::<u16>(n)?.to_be_bytes());
+            }
+            write_name(&mut buf, target)?;
+        }
+        (6, [mname, rname, serial, refresh, retry, expire, minimum]) => {
+            write_name(&mut buf, mname)?;
+            write_name(&mut buf, rname)?;
+            for n in &[serial, refresh, retry, expire, minimum] {
+                buf.extend_from_slice(&number::<u32>(n)?.to_be_bytes());
+            }
+        }
+        // TXT and SPF.
+        (16, _) | (99, _) => {
+            for string in character_strings(data)? {
+                for chunk in string.chunks(255) {
+                    buf.push(chunk.len() as u8);
+                    buf.extend_from_slice(chunk);
+                }
+                if string.is_empty() {
+                    buf.push(0);
+                }
+            }
+        }
+        (257, [flags, tag, ..]) => {
+            buf.push(number::<u8>(flags)?);
+            if tag.is_empty() || tag.len() > 255 {
+                return Err(WireError::InvalidData);
+            }
+            buf.push(tag.len() as u8);
+            buf.extend_from_slice(tag.as_bytes());
+            let value = data
+                .splitn(3, char::is_whitespace)
+                .nth(2)
+                .unwrap_or_default()
+                .trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            buf.extend_from_slice(value.as_bytes());
+        }
+        _ => return Err(WireError::InvalidData),
+    }
+    Ok(buf)
+}
+
+fn number<T: std::str::FromStr>(field: &str) -> Result<T, WireError> {
+    field.parse::<T>().map_err(|_| WireError::InvalidData)
+}
+
+// Decodes the generic format `<length> <hex>` where the hex may be split by spaces.
+fn generic_rdata(generic: &str) -> Result<Vec<u8>, WireError> {
+    let mut fields = generic.split_whitespace();
+    let len = number::<usize>(fields.next().ok_or(WireError::InvalidData)?)?;
+    let hex = fields.collect::<String>();
+    // The data comes from the servers so neither its length nor its characters are trusted.
+    if len.checked_mul(2) != Some(hex.len()) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(WireError::InvalidData);
+    }
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).map_err(|_| WireError::InvalidData)?;
+            u8::from_str_radix(pair, 16).map_err(|_| WireError::InvalidData)
+        })
+        .collect()
+}
+
+// Splits the data of a TXT record into its character strings. Cloudflare quotes each of
+// them while Google returns a single unquoted string.
+pub(crate) fn character_strings(data: &str) -> Result<Vec<Vec<u8>>, WireError> {
+    if !data.starts_with('"') {
+        return Ok(vec![data.as_bytes().to_vec()]);
+    }
+    let mut strings = Vec::new();
+    let mut bytes = data.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'"' => {
+                let mut string = Vec::new();
+                loop {
+                    match bytes.next().ok_or(WireError::InvalidData)? {
+                        b'"' => break,
+                        b'\\' => {
+                            let escaped = bytes.next().ok_or(WireError::InvalidData)?;
+                            if escaped.is_ascii_digit() {
+                                let digits = [
+                                    escaped,
+                                    bytes.next().ok_or(WireError::InvalidData)?,
+                                    bytes.next().ok_or(WireError::InvalidData)?,
+                                ];
+                                let value = std::str::from_utf8(&digits)
+                                    .ok()
+                                    .and_then(|d| d.parse::<u8>().ok())
+                                    .ok_or(WireError::InvalidData)?;
+                                string.push(value);
+                            } else {
+                                string.push(escaped);
+                            }
+                        }
+                        b => string.push(b),
+                    }
+                }
+                strings.push(string);
+            }
+            b if b.is_ascii_whitespace() => {}
+            _ => return Err(WireError::InvalidData),
+        }
+    }
+    Ok(strings)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::status::ExtendedErrorCode;
+
+    #[test]
+    fn test_extended_errors() {
+        let mut msg = vec![
+            0x12, 0x34, // ID
+            0x81, 0x82, // QR, RD, RA, SERVFAIL
+            0x00, 0x01, // QDCOUNT
+            0x00, 0x00, // ANCOUNT
+            0x00, 0x00, // NSCOUNT
+            0x00, 0x01, // ARCOUNT
+        ];
+        // dnssec-failed.org. IN A
+        msg.extend_from_slice(b"\x0ddnssec-failed\x03org\x00\x00\x01\x00\x01");
+        // OPT record with an EDE option (DNSKEY Missing) and its extra text.
+        let text = b"no SEP matching the DS found";
+        msg.extend_from_slice(&[0x00, 0x00, 0x29, 0x04, 0xd0, 0x00, 0x00, 0x00, 0x00]);
+        msg.extend_from_slice(&((text.len() + 6) as u16).to_be_bytes());
+        msg.extend_from_slice(&[0x00, 0x0f]);
+        msg.extend_from_slice(&((text.len() + 2) as u16).to_be_bytes());
+        msg.extend_from_slice(&[0x00, 0x09]);
+        msg.extend_from_slice(text);
+
+        let m = Message::from_bytes(&msg).unwrap();
+        assert_eq!(m.id, 0x1234);
+        assert_eq!(m.rcode(), RCode::ServFail);
+        assert_eq!(m.questions[0].name, "dnssec-failed.org.");
+        assert_eq!(m.questions[0].qtype, 1);
+        assert_eq!(m.opt().unwrap().class, 1232);
+        assert_eq!(
+            m.extended_errors(),
+            vec![ExtendedError {
+                code: ExtendedErrorCode::DnskeyMissing,
+                extra_text: Some("no SEP matching the DS found".to_owned()),
+            }]
+        );
+
+        // The upper bits of the RCODE are taken from the OPT record.
+        let mut badvers = msg.clone();
+        badvers[3] = 0x80;
+        badvers[40] = 0x01;
+        assert_eq!(
+            Message::from_bytes(&badvers).unwrap().rcode(),
+            RCode::BADVERS
+        );
+
+        // A message cut in the middle of the OPT record cannot be decoded.
+        assert_eq!(
+            Message::from_bytes(&msg[..msg.len() - 4]),
+            Err(WireError::Truncated)
+        );
+    }
+
+    #[test]
+    fn test_compressed_names() {
+        let mut msg = vec![0x00, 0x00, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0, 0, 0, 0];
+        msg.extend_from_slice(b"\x03www\x07example\x03com\x00\x00\x05\x00\x01");
+        // Answer owner name points to the question name.
+        msg.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x05, 0x00, 0x01, 0, 0, 0x0e, 0x10]);
+        msg.extend_from_slice(&[0x00, 0x02, 0xc0, 0x10]);
+        let m = Message::from_bytes(&msg).unwrap();
+        assert_eq!(m.answers[0].name, "www.example.com.");
+        assert_eq!(m.answers[0].ttl, 3600);
+        assert_eq!(m.answers[0].rdata, vec![0xc0, 0x10]);
+
+        // A pointer to itself is rejected.
+        let mut looped = msg[..12].to_vec();
+        looped[5] = 1;
+        looped[7] = 0;
+        looped.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01]);
+        assert_eq!(Message::from_bytes(&looped), Err(WireError::InvalidName));
+    }
+
+    #[test]
+    fn test_untrusted_counts() {
+        let mut msg = vec![0x00, 0x00, 0x81, 0x80, 0x00, 0x00, 0xff, 0xff, 0, 0, 0, 0];
+        msg.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x01, 0, 0, 0, 60, 0x00, 0x00]);
+        let mut reader = Reader { buf: &msg, pos: 12 };
+        assert_eq!(reader.records(1).unwrap().capacity(), 1);
+        reader.pos = 12;
+        assert_eq!(reader.records(65535), Err(WireError::Truncated));
+        assert_eq!(Message::from_bytes(&msg), Err(WireError::Truncated));
+    }
+
+    #[test]
+    fn test_to_bytes() {
+        let message = Message {
+            id: 0xbeef,
+            flags: 0x8180,
+            questions: vec![Question {
+                name: "www.example.com.".to_owned(),
+                qtype: 1,
+                qclass: 1,
+            }],
+            answers: vec![Record {
+                name: "www.example.com.".to_owned(),
+                rtype: 1,
+                class: 1,
+                ttl: 300,
+                rdata: vec![93, 184, 216, 34],
+            }],
+            authorities: vec![],
+            additionals: vec![Record {
+                name: ".".to_owned(),
+                rtype: RTYPE_OPT,
+                class: 1232,
+                ttl: 0,
+                rdata: vec![],
+            }],
+        };
+        let bytes = message.to_bytes().unwrap();
+        assert_eq!(&bytes[12..29], b"\x03www\x07example\x03com\x00");
+        assert_eq!(Message::from_bytes(&bytes).unwrap(), message);
+
+        // Escaped dots are part of a label.
+        let mut buf = Vec::new();
+        write_name(&mut buf, "a\\.b.c").unwrap();
+        assert_eq!(buf, b"\x03a.b\x01c\x00");
+        assert_eq!(write_name(&mut buf, "a..b."), Err(WireError::InvalidName));
+        let long = format!("{}.com.", "a".repeat(64));
+        assert_eq!(write_name(&mut buf, &long), Err(WireError::InvalidName));
+    }
+
+    #[test]
+    fn test_rdata_from_text() {
+        assert_eq!(rdata_from_text(1, "10.0.0.1").unwrap(), vec![10, 0, 0, 1]);
+        assert_eq!(rdata_from_text(28, "::1").unwrap()[15], 1);
+        assert_eq!(
+            rdata_from_text(15, "10 mail.example.com.").unwrap(),
+            b"\x00\x0a\x04mail\x07example\x03com\x00"
+        );
+        assert_eq!(
+            rdata_from_text(16, "\"v=spf1\" \"-all\"").unwrap(),
+            b"\x06v=spf1\x04-all"
+        );
+        assert_eq!(
+            rdata_from_text(16, "v=spf1 -all").unwrap(),
+            b"\x0bv=spf1 -all"
+        );
+        assert_eq!(
+            rdata_from_text(257, "0 issue \"pki.goog\"").unwrap(),
+            b"\x00\x05issuepki.goog"
+        );
+        assert_eq!(
+            rdata_from_text(99, "\\# 2 ab CD").unwrap(),
+            vec![0xab, 0xcd]
+        );
+        assert_eq!(rdata_from_text(1, "10.0.0"), Err(WireError::InvalidData));
+        assert_eq!(
+            rdata_from_text(48, "257 3 13 AAAA"),
+            Err(WireError::InvalidData)
+        );
+    }
+
+    #[test]
+    fn test_untrusted_generic_rdata() {
+        for data in &[
+            "\\# 2 a\u{e9}a",
+            "\\# 1 +1",
+            "\\# 9223372036854775808 00",
+            "\\# 18446744073709551615",
+        ] {
+            assert_eq!(rdata_from_text(99, data), Err(WireError::InvalidData));
+        }
+    }
+}